@@ -24,7 +24,7 @@ fn set_bench(c: &mut Criterion) {
         b.iter_batched(
             || {
                 let temp_dir = TempDir::new().unwrap();
-                (SledKvsEngine::new(sled::open(&temp_dir).unwrap()), temp_dir)
+                (SledKvsEngine::new(sled::open(&temp_dir).unwrap()).unwrap(), temp_dir)
             },
             |(mut db, _temp_dir)| {
                 for i in 1..(1 << 2) {
@@ -59,7 +59,7 @@ fn get_bench(c: &mut Criterion) {
     for i in &vec![2] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut db = SledKvsEngine::new(sled::open(&temp_dir).unwrap());
+            let mut db = SledKvsEngine::new(sled::open(&temp_dir).unwrap()).unwrap();
             for key_i in 1..(1 << i) {
                 db.set(format!("key{}", key_i), "value".to_string())
                     .unwrap();
@@ -0,0 +1,219 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::client::{agree, client_handshake, connect_error, unexpected, unexpected_id};
+use crate::codec::{Codec, Encoding};
+use crate::common::{Handshake, Request, Response, Tagged};
+use crate::engines::WriteBatch;
+use crate::{KvsError, Result};
+
+/// A client of a `KvsServer` or an `AsyncKvsServer` for async code.
+///
+/// It speaks the same protocol as `KvsClient` and has the same methods, which
+/// wait for the server without blocking the thread.
+pub struct AsyncKvsClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    codec: Codec,
+    // id of the next request
+    next_id: u64,
+    // features supported by both the client and the server
+    features: Vec<String>,
+}
+
+impl AsyncKvsClient {
+    /// Connects to a server and agrees on the protocol with it.
+    ///
+    /// It fails like `KvsClient::connect`.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Self::connect_with(addr, Encoding::ALL).await
+    }
+
+    /// Connects to a server, accepting only the given encodings in order of
+    /// preference.
+    pub async fn connect_with<A: ToSocketAddrs>(addr: A, encodings: &[Encoding]) -> Result<Self> {
+        let (reader, writer) = TcpStream::connect(addr).await.map_err(connect_error)?.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+
+        let handshake = serde_json::to_vec(&client_handshake(encodings))?;
+        writer.write_all(&handshake).await?;
+        writer.flush().await?;
+        let server = Handshake::read_async(&mut reader).await?;
+        let (codec, features) = agree(server)?;
+        Ok(AsyncKvsClient {
+            reader,
+            writer,
+            codec,
+            next_id: 0,
+            features,
+        })
+    }
+
+    /// Returns the encoding of the messages agreed on with the server.
+    pub fn encoding(&self) -> Encoding {
+        self.codec.encoding()
+    }
+
+    /// Tells whether both the client and the server support an optional
+    /// feature of the protocol, such as `"transactions"`.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())
+            .await?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes()).await
+    }
+
+    /// Sets the value of a key that expires after `ttl`.
+    pub async fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+            .await
+    }
+
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes()).await
+    }
+
+    /// Gets the value of a binary key.
+    pub async fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.send(&Request::Get { key }).await? {
+            Response::Value(value) => Ok(value),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    pub async fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.send_ok(&Request::Set {
+            key,
+            value,
+            ttl: None,
+        })
+        .await
+    }
+
+    pub async fn set_bytes_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.send_ok(&Request::Set {
+            key,
+            value,
+            ttl: Some(ttl),
+        })
+        .await
+    }
+
+    pub async fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.send_ok(&Request::Remove { key }).await
+    }
+
+    pub async fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        self.send_ok(&Request::Batch { batch }).await
+    }
+
+    /// Sets `key` to `new` if its current value is `expected`.
+    ///
+    /// `None` stands for a missing key. Returns whether the swap took place.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+        .await
+    }
+
+    pub async fn compare_and_swap_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        match self
+            .send(&Request::CompareAndSwap { key, expected, new })
+            .await?
+        {
+            Response::Swapped(swapped) => Ok(swapped),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Begins a transaction on the connection, like `KvsClient::begin`.
+    pub async fn begin(&mut self) -> Result<()> {
+        self.send_ok(&Request::Begin).await
+    }
+
+    /// Commits the transaction of the connection.
+    ///
+    /// It returns `KvsError::Conflict` if a key the transaction read has been
+    /// changed since it began, in which case nothing is written.
+    pub async fn commit(&mut self) -> Result<()> {
+        match self.send(&Request::Commit).await? {
+            Response::Ok => Ok(()),
+            Response::Conflict => Err(KvsError::Conflict),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Discards the transaction of the connection.
+    pub async fn abort(&mut self) -> Result<()> {
+        self.send_ok(&Request::Abort).await
+    }
+
+    /// Checks that the server answers on the connection, like `KvsClient::ping`.
+    pub async fn ping(&mut self) -> Result<()> {
+        self.send_ok(&Request::Ping).await
+    }
+
+    /// Sends a request and returns the response, failing if it is an error.
+    ///
+    /// A request cancelled before its response is read leaves the connection
+    /// out of step, so later requests fail rather than get the wrong response.
+    async fn send(&mut self, req: &Request) -> Result<Response> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.codec
+            .write_async(&mut self.writer, &Tagged { id, msg: req })
+            .await?;
+        self.writer.flush().await?;
+        let resp: Tagged<Response> = self
+            .codec
+            .read_async(&mut self.reader)
+            .await?
+            .ok_or_else(|| KvsError::StringError("Connection closed by the server".to_owned()))?;
+        if resp.id != id {
+            return Err(unexpected_id(resp.id));
+        }
+        match resp.msg {
+            Response::Err { code, message } => Err(KvsError::from_remote(code, message)),
+            resp => Ok(resp),
+        }
+    }
+
+    /// Sends a request that returns nothing on success.
+    async fn send_ok(&mut self, req: &Request) -> Result<()> {
+        match self.send(req).await? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use crate::common::{Handshake, Request, Tagged};
+use crate::engines::{AsyncEngine, KvsEngine, Transaction};
+use crate::server;
+use crate::shutdown::ShutdownHandle;
+use crate::Result;
+
+/// How long a server shutting down waits for its connections by default.
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A server of the kvs protocol for async code, running on the tokio runtime
+/// it is awaited in.
+///
+/// It answers `KvsClient` and `AsyncKvsClient` alike. Each connection is a
+/// task, and the engine calls run on the blocking threads of the runtime.
+pub struct AsyncKvsServer<E: KvsEngine> {
+    engine: AsyncEngine<E>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+}
+
+impl<E: KvsEngine> AsyncKvsServer<E> {
+    pub fn new(engine: E) -> Self {
+        AsyncKvsServer {
+            engine: AsyncEngine::new(engine),
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+
+    /// Sets how long the server waits, once asked to shut down, for the open
+    /// connections to finish the requests they are handling before closing them.
+    ///
+    /// It is 30 seconds by default.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Returns a handle to shut the server down once it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves the clients until the server is shut down through a
+    /// `ShutdownHandle`.
+    ///
+    /// It returns once the connections are closed and the engine is flushed.
+    pub async fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        self.shutdown.add_listener(listener.local_addr()?);
+        // tells the connections to close once they are between requests
+        let (stop, stopped) = watch::channel(());
+        let mut connections = JoinSet::new();
+
+        while !self.shutdown.is_shutdown() {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            // the shutdown handle wakes the server with a connection of its own
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            let engine = self.engine.clone();
+            let stopped = stopped.clone();
+            connections.spawn(async move {
+                if let Err(e) = serve(engine, stream, peer_addr, stopped).await {
+                    error!("Error on serving client: {}", e);
+                }
+            });
+            // forget the connections already closed
+            while connections.try_join_next().is_some() {}
+        }
+
+        info!("Shutting down");
+        let _ = stop.send(());
+        let drained = async { while connections.join_next().await.is_some() {} };
+        if tokio::time::timeout(self.shutdown_timeout, drained)
+            .await
+            .is_err()
+        {
+            warn!("Closing {} connections still busy", connections.len());
+            connections.shutdown().await;
+        }
+        self.engine.flush().await?;
+        info!("Server stopped");
+        Ok(())
+    }
+}
+
+async fn serve<E: KvsEngine>(
+    engine: AsyncEngine<E>,
+    tcp: TcpStream,
+    peer_addr: SocketAddr,
+    mut stopped: watch::Receiver<()>,
+) -> Result<()> {
+    info!("Accepted connection from {}", peer_addr);
+    let (reader, writer) = tcp.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    if !wait_for_request(&mut reader, &mut stopped).await? {
+        return Ok(());
+    }
+
+    let client = Handshake::read_async(&mut reader).await?;
+    let (handshake, codec) = server::answer_handshake(&client, peer_addr);
+    writer.write_all(&serde_json::to_vec(&handshake)?).await?;
+    writer.flush().await?;
+    let Some(codec) = codec else {
+        return Ok(());
+    };
+
+    // the transaction begun on this connection, aborted if the client goes away
+    let mut txn: Option<Transaction<E>> = None;
+
+    // requests are handled in the order they arrive
+    while wait_for_request(&mut reader, &mut stopped).await? {
+        let Some(Tagged { id, msg: req }) =
+            codec.read_async::<_, Tagged<Request>>(&mut reader).await?
+        else {
+            break;
+        };
+        debug!("Receive request from {}: {:?}", peer_addr, req);
+        // the transaction goes along to the blocking thread and back
+        let (resp, returned_txn) = engine
+            .run(move |engine| {
+                let resp = server::handle(engine, &mut txn, req);
+                Ok((resp, txn))
+            })
+            .await?;
+        txn = returned_txn;
+        let resp = Tagged { id, msg: resp };
+        codec.write_async(&mut writer, &resp).await?;
+        // responses to pipelined requests are sent together
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+        debug!("Resonse sent to {}: {:?}", peer_addr, resp);
+    }
+    Ok(())
+}
+
+/// Waits for the next request on the connection.
+///
+/// Returns false if the connection is closed, or the server shuts down first.
+async fn wait_for_request(
+    reader: &mut BufReader<OwnedReadHalf>,
+    stopped: &mut watch::Receiver<()>,
+) -> Result<bool> {
+    if !reader.buffer().is_empty() {
+        return Ok(true);
+    }
+    tokio::select! {
+        buf = reader.fill_buf() => Ok(!buf?.is_empty()),
+        _ = stopped.changed() => Ok(false),
+    }
+}
@@ -1,13 +1,15 @@
 use clap::{Parser, Subcommand, Args};
 
 use std::net::SocketAddr;
+use std::time::Duration;
 
-use kvs::{Result, KvsClient};
+use kvs::{ErrorCode, Result, KvsClient, KvsError};
 
 
 #[derive(Parser)]
 #[clap(name = "kvs-client")]
 #[clap(author, version, about, long_about = None)]
+#[clap(after_help = EXIT_CODES)]
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
@@ -20,7 +22,9 @@ enum Commands {
     /// Set the value of a string key to a string
     Set(Set),
     /// Remove a given string key.
-    Rm(Rm)
+    Rm(Rm),
+    /// Set or remove a key only if its current value matches
+    Cas(Cas),
 }
 
 
@@ -43,6 +47,12 @@ struct Set {
     key: String,
     #[clap(help = "The string value of the key")]
     value: String,
+    #[clap(
+        long,
+        name = "SECONDS",
+        help = "Removes the key after the given number of seconds",
+    )]
+    ttl: Option<u64>,
     #[clap(
         long,
         name = "ADDRESS_FORMAT",
@@ -66,12 +76,67 @@ struct Rm {
 }
 
 
+#[derive(Args)]
+struct Cas {
+    #[clap(help = "A string key")]
+    key: String,
+    #[clap(
+        long,
+        name = "EXPECTED",
+        help = "The value the key must have, the key must not exist if omitted",
+    )]
+    expected: Option<String>,
+    #[clap(
+        long,
+        name = "NEW",
+        help = "The new value of the key, the key is removed if omitted",
+    )]
+    new: Option<String>,
+    #[clap(
+        long,
+        name = "ADDRESS_FORMAT",
+        default_value = "127.0.0.1:4000",
+        help = "Sets the server address",
+    )]
+    addr: SocketAddr
+}
+
+
+const EXIT_CODES: &str = "\
+EXIT CODES:
+    0    Success
+    1    Other error
+    2    Invalid arguments
+    3    Key not found
+    4    Transaction conflict or value mismatch
+    5    Store opened read-only
+    6    Invalid request
+    7    Server unavailable
+    8    I/O error
+    9    Corrupted store";
+
+
 fn main() {
     let cli = Cli::parse();
 
     if let Err(e) = run(cli) {
         eprintln!("{}", e);
-        std::process::exit(1);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+// The exit code of each kind of error, as listed in EXIT_CODES. Clap exits
+// with 2 on invalid arguments.
+fn exit_code(err: &KvsError) -> i32 {
+    match err.code() {
+        ErrorCode::Internal => 1,
+        ErrorCode::KeyNotFound => 3,
+        ErrorCode::Conflict => 4,
+        ErrorCode::ReadOnly => 5,
+        ErrorCode::InvalidRequest => 6,
+        ErrorCode::Unavailable => 7,
+        ErrorCode::Io => 8,
+        ErrorCode::Corruption => 9,
     }
 }
 
@@ -86,14 +151,30 @@ fn run(cli: Cli) -> Result<()> {
                 println!("Key not found");
             }
         },
-        Commands::Set(Set{ key, value, addr }) => {
+        Commands::Set(Set{ key, value, ttl, addr }) => {
             let mut client = KvsClient::connect(addr)?;
-            client.set(key.to_string(), value.to_string())?
+            match ttl {
+                Some(ttl) => client.set_with_ttl(
+                    key.to_string(),
+                    value.to_string(),
+                    Duration::from_secs(*ttl)
+                )?,
+                None => client.set(key.to_string(), value.to_string())?,
+            }
         },
         Commands::Rm(Rm{ key, addr }) => {
             let mut client = KvsClient::connect(addr)?;
             client.remove(key.to_string())?;
         },
+        Commands::Cas(Cas{ key, expected, new, addr }) => {
+            let mut client = KvsClient::connect(addr)?;
+            if !client.compare_and_swap(key.to_string(), expected.clone(), new.clone())? {
+                return Err(KvsError::Remote {
+                    code: ErrorCode::Conflict,
+                    message: "Value mismatch".to_owned(),
+                });
+            }
+        },
     }
     Ok(())
 }
\ No newline at end of file
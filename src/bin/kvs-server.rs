@@ -1,20 +1,25 @@
 use clap::{Parser, ArgEnum};
 
-use kvs::server::KvsServer;
+use kvs::server::{KvsServer, Protocol};
 use kvs::thread_pool::{RayonThreadPool, ThreadPool};
 use log::{info, warn, error, LevelFilter};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 
-use std::{fs, fmt};
+use std::{fs, fmt, thread};
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::env::current_dir;
 use std::str::FromStr;
 use std::process::exit;
+use std::time::Duration;
 
 use kvs::{Result, KvsError};
-use kvs::engines::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::engines::{KvStore, KvStoreOptions, KvsEngine, SledKvsEngine, SyncPolicy};
 
 
 const DEFAULT_ENGINE: Engine = Engine::kvs;
+const THREADS: u32 = 4;
 
 
 #[derive(Parser)]
@@ -34,7 +39,96 @@ struct Cli {
         help = "Sets the server engine",
     )]
     #[clap(arg_enum)]
-    engine: Option<Engine>
+    engine: Option<Engine>,
+    #[clap(
+        long,
+        name = "POLICY",
+        help = "Sets when writes are fsynced: always, never or an interval like 100ms",
+        parse(try_from_str = parse_sync_policy),
+    )]
+    sync: Option<SyncPolicy>,
+    #[clap(
+        long,
+        name = "PROTOCOL_NAME",
+        default_value = "kvs",
+        help = "Sets the protocol spoken on the server address",
+    )]
+    #[clap(arg_enum)]
+    protocol: ProtocolName,
+    #[clap(
+        long,
+        name = "RESP_ADDRESS",
+        help = "Also serves the RESP protocol on the given address",
+    )]
+    resp_addr: Option<SocketAddr>,
+    #[clap(
+        long,
+        name = "HTTP_ADDRESS",
+        help = "Also serves the HTTP gateway on the given address",
+    )]
+    http_addr: Option<SocketAddr>,
+    #[clap(
+        long,
+        name = "MEMCACHED_ADDRESS",
+        help = "Also serves the memcached protocol on the given address",
+    )]
+    memcached_addr: Option<SocketAddr>,
+    #[clap(
+        long,
+        name = "SECONDS",
+        default_value = "30",
+        help = "Sets how long open connections are given to finish on shutdown",
+    )]
+    shutdown_timeout: u64,
+    #[clap(
+        long,
+        name = "CONNECTIONS",
+        help = "Sets how many connections may be open at once, one per thread by default \
+            and unlimited with --io-threads",
+    )]
+    max_connections: Option<u64>,
+    #[clap(
+        long,
+        name = "IO_THREADS",
+        help = "Serves the kvs protocol from the given number of event loops, \
+            so that idle connections take no thread",
+    )]
+    io_threads: Option<NonZeroUsize>,
+    #[clap(
+        long,
+        name = "IDLE_SECONDS",
+        default_value = "300",
+        help = "Closes the connections idle for the given number of seconds, 0 for never",
+    )]
+    idle_timeout: u64,
+    #[clap(
+        long,
+        name = "REQUEST_SECONDS",
+        default_value = "30",
+        help = "Closes the connections slower to send a request or read a response, 0 for never",
+    )]
+    request_timeout: u64,
+}
+
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, PartialEq, Eq, ArgEnum, Debug)]
+enum ProtocolName {
+    kvs,
+    resp,
+    http,
+    memcached,
+}
+
+impl From<ProtocolName> for Protocol {
+    fn from(name: ProtocolName) -> Protocol {
+        match name {
+            ProtocolName::kvs => Protocol::Kvs,
+            ProtocolName::resp => Protocol::Resp,
+            ProtocolName::http => Protocol::Http,
+            ProtocolName::memcached => Protocol::Memcached,
+        }
+    }
 }
 
 
@@ -66,6 +160,10 @@ impl fmt::Display for Engine {
     }
 }
 
+fn parse_sync_policy(s: &str) -> std::result::Result<SyncPolicy, String> {
+    s.parse().map_err(|e: KvsError| e.to_string())
+}
+
 fn main() {
     env_logger::builder().filter_level(LevelFilter::Info).init();
 
@@ -95,32 +193,96 @@ fn run(cli: Cli) -> Result<()> {
 
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {:?}", engine);
-    info!("Listening on {:?}", addr);
+    if let Some(sync) = cli.sync {
+        info!("Sync policy: {}", sync);
+    }
+    info!("Listening on {:?} ({:?} protocol)", addr, cli.protocol);
+    if let Some(resp_addr) = cli.resp_addr {
+        info!("Listening on {:?} (resp protocol)", resp_addr);
+    }
+    if let Some(http_addr) = cli.http_addr {
+        info!("Listening on {:?} (http protocol)", http_addr);
+    }
+    if let Some(memcached_addr) = cli.memcached_addr {
+        info!("Listening on {:?} (memcached protocol)", memcached_addr);
+    }
 
     let workdir = current_dir()?;
     fs::write(workdir.join("engine"), format!("{engine}"))?;
 
     match engine {
         Engine::kvs => {
+            let mut options = KvStoreOptions::new();
+            if let Some(sync) = cli.sync {
+                options = options.sync_policy(sync);
+            }
             run_with_engine(
-                KvStore::open(workdir)?,
-                addr
+                KvStore::open_with(workdir, options)?,
+                &cli
             )
         },
         Engine::sled => {
-            run_with_engine(
-                SledKvsEngine::new(sled::open(workdir)?),
-                addr
-            )
+            let db = sled::open(workdir)?;
+            let engine = match cli.sync {
+                Some(sync) => SledKvsEngine::with_sync_policy(db, sync)?,
+                None => SledKvsEngine::new(db)?,
+            };
+            run_with_engine(engine, &cli)
         },
     }
 }
 
-fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let pool = RayonThreadPool::new(4)?;
-    // let pool = SharedQueueThreadPool::new(4)?;
-    let server = KvsServer::new(engine, pool);
-    server.run(addr)
+fn run_with_engine<E: KvsEngine>(engine: E, cli: &Cli) -> Result<()> {
+    let pool = RayonThreadPool::new(THREADS)?;
+    // let pool = SharedQueueThreadPool::new(THREADS)?;
+    let mut server = KvsServer::new(engine, pool)
+        .protocol(cli.protocol.into());
+    if let Some(io_threads) = cli.io_threads {
+        server = server.event_loops(io_threads.get());
+    }
+    // a connection over the number of threads would wait for one, so it is
+    // turned down by default unless the event loops serve it
+    let max_connections = match cli.io_threads {
+        Some(_) => cli.max_connections,
+        None => Some(cli.max_connections.unwrap_or(THREADS.into())),
+    };
+    if let Some(max_connections) = max_connections {
+        server = server.max_connections(max_connections);
+    }
+    if cli.idle_timeout > 0 {
+        server = server.idle_timeout(Duration::from_secs(cli.idle_timeout));
+    }
+    if cli.request_timeout > 0 {
+        server = server.request_timeout(Duration::from_secs(cli.request_timeout));
+    }
+    if let Some(resp_addr) = cli.resp_addr {
+        server = server.listen(resp_addr, Protocol::Resp)?;
+    }
+    if let Some(http_addr) = cli.http_addr {
+        server = server.listen(http_addr, Protocol::Http)?;
+    }
+    if let Some(memcached_addr) = cli.memcached_addr {
+        server = server.listen(memcached_addr, Protocol::Memcached)?;
+    }
+    let server = server.shutdown_timeout(Duration::from_secs(cli.shutdown_timeout));
+
+    // The first SIGTERM or SIGINT shuts the server down gracefully, the next
+    // one exits at once.
+    let handle = server.shutdown_handle();
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            info!("Received signal {}, shutting down", signal);
+            handle.shutdown();
+        }
+        if signals.next().is_some() {
+            warn!("Received another signal, exiting");
+            exit(1);
+        }
+    });
+
+    server.run(cli.addr)
 }
 
 
@@ -1,59 +1,458 @@
-use serde::Deserialize;
-use serde_json::de::{IoRead, Deserializer};
-
 use std::{
-    net::{TcpStream, ToSocketAddrs},
-    io::{BufReader, BufWriter, Write}
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    io::{self, BufReader, BufWriter, Write},
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::{Result,KvsError, common::RemoveResponse};
-use crate::common::{Request, GetResponse, SetResponse};
+use crate::{Result, KvsError};
+use crate::codec::{Codec, Encoding};
+use crate::common::{Handshake, Request, Response, Tagged, FEATURES, PROTOCOL_VERSION};
+use crate::engines::WriteBatch;
 
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
+    codec: Codec,
+    // id of the next request
+    next_id: u64,
+    // features supported by both the client and the server
+    features: Vec<String>,
+    // whether a transaction begun on the connection is in progress
+    in_transaction: bool,
+    // whether the connection failed, which leaves it unusable
+    broken: bool,
 }
 
 impl KvsClient {
+    /// Connects to a server and agrees on the protocol with it.
+    ///
+    /// It returns `KvsError::ProtocolMismatch` if the server speaks another
+    /// version of the protocol, and `KvsError::Unavailable` if the server turns
+    /// the connection down.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let reader = TcpStream::connect(addr)?;
+        Self::connect_with(addr, Encoding::ALL)
+    }
+
+    /// Connects to a server, accepting only the given encodings in order of
+    /// preference.
+    pub fn connect_with<A: ToSocketAddrs>(addr: A, encodings: &[Encoding]) -> Result<Self> {
+        let stream = TcpStream::connect(addr).map_err(connect_error)?;
+        Self::handshake(stream, encodings)
+    }
+
+    /// Connects to one of the addresses and agrees on the protocol, failing
+    /// with a timeout error if that does not happen before `deadline`.
+    pub(crate) fn connect_before(
+        addrs: &[SocketAddr],
+        encodings: &[Encoding],
+        deadline: Instant,
+    ) -> Result<Self> {
+        let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "No address to connect to");
+        for addr in addrs {
+            let stream = match TcpStream::connect_timeout(addr, time_left(deadline)?) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    last_err = e;
+                    continue;
+                }
+            };
+            stream.set_read_timeout(Some(time_left(deadline)?))?;
+            stream.set_write_timeout(Some(time_left(deadline)?))?;
+            let client = Self::handshake(stream, encodings)?;
+            client.set_timeout(None)?;
+            return Ok(client);
+        }
+        Err(connect_error(last_err))
+    }
+
+    /// Agrees on the protocol with the server at the other end of the stream.
+    fn handshake(reader: TcpStream, encodings: &[Encoding]) -> Result<Self> {
         // TODO what try_clone does ?
         let writer = reader.try_clone()?;
-        Ok(KvsClient { 
-            reader: Deserializer::from_reader(
-                BufReader::new(reader)
-            ),
-            writer: BufWriter::new(writer)
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+
+        serde_json::to_writer(&mut writer, &client_handshake(encodings))?;
+        writer.flush()?;
+        let server = Handshake::read(&mut reader)?;
+        let (codec, features) = agree(server)?;
+        Ok(KvsClient {
+            reader,
+            writer,
+            codec,
+            next_id: 0,
+            features,
+            in_transaction: false,
+            broken: false,
         })
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
-        match resp {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(err) => Err(KvsError::StringError(err))
+    /// Returns the encoding of the messages agreed on with the server.
+    pub fn encoding(&self) -> Encoding {
+        self.codec.encoding()
+    }
+
+    /// Starts a pipeline of requests sent without waiting for their responses.
+    ///
+    /// ```rust,no_run
+    /// # use kvs::{KvsClient, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// let mut pipeline = client.pipeline();
+    /// for i in 0..1000 {
+    ///     pipeline.set(format!("key{}", i), i.to_string());
+    /// }
+    /// for result in pipeline.execute()? {
+    ///     result?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
         }
     }
 
+    /// Tells whether both the client and the server support an optional
+    /// feature of the protocol, such as `"transactions"`.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.reader)?;
-        match resp {
-            SetResponse::Ok => Ok(()),
-            SetResponse::Err(err) => Err(KvsError::StringError(err))
-        }
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets the value of a key that expires after `ttl`.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Gets the value of a binary key.
+    ///
+    /// Unlike `get`, the value may be any bytes.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.send(&Request::Get { key })? {
+            Response::Value(value) => Ok(value),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.send_ok(&Request::Set { key, value, ttl: None })
+    }
+
+    pub fn set_bytes_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.send_ok(&Request::Set { key, value, ttl: Some(ttl) })
+    }
+
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.send_ok(&Request::Remove { key })
+    }
+
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        self.send_ok(&Request::Batch { batch })
+    }
+
+    /// Sets `key` to `new` if its current value is `expected`.
+    ///
+    /// `None` stands for a missing key. Returns whether the swap took place.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    pub fn compare_and_swap_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        match self.send(&Request::CompareAndSwap { key, expected, new })? {
+            Response::Swapped(swapped) => Ok(swapped),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Begins a transaction on the connection.
+    ///
+    /// `get`, `set` and `remove` are part of the transaction until `commit` or
+    /// `abort` is called. The server aborts it if the connection is closed.
+    pub fn begin(&mut self) -> Result<()> {
+        self.send_ok(&Request::Begin)?;
+        self.in_transaction = true;
+        Ok(())
+    }
+
+    /// Commits the transaction of the connection.
+    ///
+    /// It returns `KvsError::Conflict` if a key the transaction read has been
+    /// changed since it began, in which case nothing is written.
+    pub fn commit(&mut self) -> Result<()> {
+        // the server ends the transaction whatever the outcome
+        self.in_transaction = false;
+        match self.send(&Request::Commit)? {
+            Response::Ok => Ok(()),
+            Response::Conflict => Err(KvsError::Conflict),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Discards the transaction of the connection.
+    pub fn abort(&mut self) -> Result<()> {
+        self.in_transaction = false;
+        self.send_ok(&Request::Abort)
+    }
+
+    /// Checks that the server answers on the connection.
+    ///
+    /// The server must support the `"ping"` feature.
+    pub fn ping(&mut self) -> Result<()> {
+        self.send_ok(&Request::Ping)
+    }
+
+    /// Sets how long reading or writing the connection may block, `None`
+    /// standing for ever. A request timing out breaks the connection.
+    pub(crate) fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        // the writer is a clone of the same socket, which shares the timeouts
+        let stream = self.reader.get_ref();
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)
+    }
+
+    /// Tells whether a transaction begun with `begin` is in progress.
+    pub(crate) fn in_transaction(&self) -> bool {
+        self.in_transaction
+    }
+
+    /// Tells whether the connection has failed, in which case every request
+    /// fails as well.
+    pub(crate) fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Sends a request and returns the response, failing if it is an error.
+    fn send(&mut self, req: &Request) -> Result<Response> {
+        let resp = self.exchange(req);
+        if resp.is_err() {
+            self.broken = true;
+        }
+        match resp? {
+            Response::Err { code, message } => Err(KvsError::from_remote(code, message)),
+            resp => Ok(resp),
+        }
+    }
+
+    /// Sends a request and reads its response.
+    fn exchange(&mut self, req: &Request) -> Result<Response> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.codec.write(&mut self.writer, &Tagged { id, msg: req })?;
         self.writer.flush()?;
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
-        match resp {
-            RemoveResponse::Ok => Ok(()),
-            RemoveResponse::Err(err) => Err(KvsError::StringError(err))
+        let resp = receive(self.codec, &mut self.reader)?;
+        if resp.id != id {
+            return Err(unexpected_id(resp.id));
+        }
+        Ok(resp.msg)
+    }
+
+    /// Sends a request that returns nothing on success.
+    fn send_ok(&mut self, req: &Request) -> Result<()> {
+        match self.send(req)? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
         }
     }
+}
+
+/// Turns a failure to connect into `KvsError::Unavailable` if no server
+/// answered, which tells it apart from failing to talk to one.
+pub(crate) fn connect_error(err: io::Error) -> KvsError {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::TimedOut => {
+            KvsError::Unavailable(format!("Cannot connect to the server: {}", err))
+        }
+        _ => err.into(),
+    }
+}
+
+/// Returns the time left before `deadline`, or a timeout error if it has passed.
+pub(crate) fn time_left(deadline: Instant) -> io::Result<Duration> {
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(left) if !left.is_zero() => Ok(left),
+        _ => Err(io::ErrorKind::TimedOut.into()),
+    }
+}
+
+/// The handshake of a client accepting the given encodings.
+pub(crate) fn client_handshake(encodings: &[Encoding]) -> Handshake {
+    Handshake {
+        version: PROTOCOL_VERSION,
+        features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        encodings: encodings.iter().map(|encoding| encoding.to_string()).collect(),
+        error: None,
+    }
+}
+
+/// Checks the handshake the server answered with, and returns the codec and
+/// the features agreed on.
+pub(crate) fn agree(server: Handshake) -> Result<(Codec, Vec<String>)> {
+    if let Some(error) = server.error {
+        return Err(KvsError::Unavailable(error));
+    }
+    if server.version != PROTOCOL_VERSION {
+        return Err(KvsError::ProtocolMismatch {
+            client: PROTOCOL_VERSION,
+            server: server.version,
+        });
+    }
+    let encoding = match server.encodings.first() {
+        Some(encoding) => encoding.parse()?,
+        None => {
+            let msg = "The server supports none of the encodings";
+            return Err(KvsError::StringError(msg.to_owned()));
+        }
+    };
+    Ok((Codec::new(encoding), server.features))
+}
+
+pub(crate) fn unexpected(resp: Response) -> KvsError {
+    KvsError::StringError(format!("Unexpected response: {:?}", resp))
+}
+
+pub(crate) fn unexpected_id(id: u64) -> KvsError {
+    KvsError::StringError(format!("Unexpected response to request {}", id))
+}
+
+fn receive(codec: Codec, reader: &mut BufReader<TcpStream>) -> Result<Tagged<Response>> {
+    codec.read(reader)?
+        .ok_or_else(|| KvsError::StringError("Connection closed by the server".to_owned()))
+}
+
+/// Requests sent together over the connection of a `KvsClient`.
+///
+/// It is returned by `KvsClient::pipeline`. Nothing is sent until `execute`
+/// is called, which writes every request before the first response is needed,
+/// so the whole pipeline pays for a single round trip.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.get_bytes(key.into_bytes())
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> &mut Self {
+        self.requests.push(Request::Get { key });
+        self
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.requests.push(Request::Set { key, value, ttl: None });
+        self
+    }
+
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> &mut Self {
+        self.requests.push(Request::Remove { key });
+        self
+    }
+
+    /// Sends the requests and returns their results in the same order.
+    ///
+    /// A get results in the value of its key and the other requests in `None`.
+    /// A request failing does not stop the ones after it. The outer error is
+    /// for the connection failing, which leaves the client unusable.
+    pub fn execute(self) -> Result<Vec<Result<Option<Vec<u8>>>>> {
+        let KvsClient { reader, writer, codec, next_id, broken, .. } = self.client;
+        let codec = *codec;
+        let first_id = *next_id;
+        let count = self.requests.len();
+        *next_id += count as u64;
+
+        // Responses are read while requests are still written. Otherwise the
+        // server could block on a full connection, waiting for them to be read.
+        let (sent, received) = thread::scope(|scope| {
+            let receiver = scope.spawn(move || {
+                (0..count).map(|_| receive(codec, reader)).collect::<Result<Vec<_>>>()
+            });
+            let sent = send_all(codec, writer, first_id, self.requests);
+            if sent.is_err() {
+                // let the server close the connection, so the receiver stops waiting
+                let _ = writer.get_ref().shutdown(Shutdown::Write);
+            }
+            (sent, receiver.join().unwrap())
+        });
+        if sent.is_err() || received.is_err() {
+            *broken = true;
+        }
+        sent?;
+
+        let mut results: Vec<Option<Result<Option<Vec<u8>>>>> = (0..count).map(|_| None).collect();
+        for resp in received? {
+            let Some(slot) = resp.id.checked_sub(first_id)
+                .and_then(|index| results.get_mut(index as usize))
+                .filter(|slot| slot.is_none())
+            else {
+                *broken = true;
+                return Err(unexpected_id(resp.id));
+            };
+            *slot = Some(match resp.msg {
+                Response::Ok => Ok(None),
+                Response::Value(value) => Ok(value),
+                Response::Err { code, message } => Err(KvsError::from_remote(code, message)),
+                resp => Err(unexpected(resp)),
+            });
+        }
+        // each of the `count` responses filled a different slot
+        Ok(results.into_iter().map(Option::unwrap).collect())
+    }
+}
+
+fn send_all(
+    codec: Codec,
+    writer: &mut BufWriter<TcpStream>,
+    first_id: u64,
+    requests: Vec<Request>,
+) -> Result<()> {
+    for (id, req) in (first_id..).zip(requests) {
+        codec.write(writer, &Tagged { id, msg: req })?;
+    }
+    writer.flush()?;
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,312 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::client::{time_left, KvsClient};
+use crate::codec::Encoding;
+use crate::{KvsError, Result};
+
+/// Default number of connections a pool opens at most.
+const DEFAULT_MAX_CONNECTIONS: usize = 8;
+/// Default time a checkout waits for a connection.
+const DEFAULT_CHECKOUT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Options to configure a `KvsClientPool`.
+///
+/// ```rust,no_run
+/// # use kvs::{KvsClientPool, KvsClientPoolOptions, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::time::Duration;
+/// let options = KvsClientPoolOptions::new()
+///     .min_connections(2)
+///     .max_connections(16)
+///     .checkout_timeout(Duration::from_secs(1));
+/// let pool = KvsClientPool::connect_with("127.0.0.1:4000", options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvsClientPoolOptions {
+    min_connections: usize,
+    max_connections: usize,
+    checkout_timeout: Duration,
+    validate: bool,
+    encodings: Vec<Encoding>,
+}
+
+impl Default for KvsClientPoolOptions {
+    fn default() -> KvsClientPoolOptions {
+        KvsClientPoolOptions {
+            min_connections: 0,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            checkout_timeout: DEFAULT_CHECKOUT_TIMEOUT,
+            validate: true,
+            encodings: Encoding::ALL.to_vec(),
+        }
+    }
+}
+
+impl KvsClientPoolOptions {
+    /// Creates options with the default settings.
+    pub fn new() -> KvsClientPoolOptions {
+        KvsClientPoolOptions::default()
+    }
+
+    /// Sets how many connections are opened along with the pool, so that the
+    /// first checkouts do not wait for them. Defaults to 0.
+    pub fn min_connections(mut self, min: usize) -> KvsClientPoolOptions {
+        self.min_connections = min;
+        self
+    }
+
+    /// Sets how many connections may be open at once, idle or checked out.
+    /// Defaults to 8.
+    pub fn max_connections(mut self, max: usize) -> KvsClientPoolOptions {
+        self.max_connections = max;
+        self
+    }
+
+    /// Sets how long a checkout waits for a connection when they are all
+    /// checked out. It also bounds the time spent pinging or opening the
+    /// connection. Defaults to 30 seconds.
+    pub fn checkout_timeout(mut self, timeout: Duration) -> KvsClientPoolOptions {
+        self.checkout_timeout = timeout;
+        self
+    }
+
+    /// Sets whether an idle connection is pinged before it is checked out, so
+    /// that one closed by the server is replaced rather than handed out.
+    /// Connections to servers without the `"ping"` feature are not pinged.
+    /// Defaults to `true`.
+    pub fn validate_on_checkout(mut self, validate: bool) -> KvsClientPoolOptions {
+        self.validate = validate;
+        self
+    }
+
+    /// Sets the encodings the connections accept in order of preference.
+    /// Defaults to every encoding.
+    pub fn encodings(mut self, encodings: &[Encoding]) -> KvsClientPoolOptions {
+        self.encodings = encodings.to_vec();
+        self
+    }
+}
+
+/// Connections to a server shared by many threads.
+///
+/// `get` checks a connection out, which goes back to the pool when the
+/// returned `PooledClient` is dropped. A connection that failed is closed
+/// instead, and a new one takes its place on a later checkout. The pool is
+/// meant to be shared behind an `Arc`.
+///
+/// ```rust,no_run
+/// # use kvs::{KvsClientPool, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::sync::Arc;
+/// use std::thread;
+/// let pool = Arc::new(KvsClientPool::connect("127.0.0.1:4000")?);
+/// let handles: Vec<_> = (0..4)
+///     .map(|i| {
+///         let pool = Arc::clone(&pool);
+///         thread::spawn(move || pool.get()?.set(format!("key{}", i), i.to_string()))
+///     })
+///     .collect();
+/// for handle in handles {
+///     handle.join().unwrap()?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvsClientPool {
+    addrs: Vec<SocketAddr>,
+    options: KvsClientPoolOptions,
+    state: Mutex<State>,
+    // notified whenever a connection goes back to the pool or is closed
+    available: Condvar,
+}
+
+struct State {
+    idle: Vec<KvsClient>,
+    // connections idle or checked out, and ones being opened
+    open: usize,
+}
+
+impl KvsClientPool {
+    /// Creates a pool of connections to a server with the default options.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<KvsClientPool> {
+        KvsClientPool::connect_with(addr, KvsClientPoolOptions::default())
+    }
+
+    /// Creates a pool of connections to a server with the given options.
+    ///
+    /// It fails if any of the `min_connections` connections fails to open.
+    pub fn connect_with<A: ToSocketAddrs>(
+        addr: A,
+        options: KvsClientPoolOptions,
+    ) -> Result<KvsClientPool> {
+        assert!(
+            options.max_connections > 0,
+            "the maximum number of connections must not be zero"
+        );
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        let pool = KvsClientPool {
+            addrs,
+            options,
+            state: Mutex::new(State {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            available: Condvar::new(),
+        };
+        let min = pool.options.min_connections.min(pool.options.max_connections);
+        let idle = (0..min)
+            .map(|_| KvsClient::connect_with(&pool.addrs[..], &pool.options.encodings))
+            .collect::<Result<Vec<_>>>()?;
+        let mut state = pool.lock();
+        state.open = idle.len();
+        state.idle = idle;
+        drop(state);
+        Ok(pool)
+    }
+
+    /// Checks a connection out of the pool.
+    ///
+    /// It reuses an idle connection if there is one, and opens a new one
+    /// otherwise unless `max_connections` are open. Then it waits for one to
+    /// come back. It returns `KvsError::Unavailable` if no connection is ready
+    /// within the checkout timeout, a server that stops answering included.
+    pub fn get(&self) -> Result<PooledClient<'_>> {
+        let deadline = Instant::now() + self.options.checkout_timeout;
+        let mut state = self.lock();
+        loop {
+            if let Some(mut client) = state.idle.pop() {
+                drop(state);
+                // a server without pings cannot be checked, so the connection is trusted
+                let validate = self.options.validate && client.supports("ping");
+                if !validate || ping_before(&mut client, deadline) {
+                    return Ok(self.checked_out(client));
+                }
+                if Instant::now() >= deadline {
+                    // no time is left to replace it, and it is closed if the ping broke it
+                    self.put_back(client);
+                    return Err(timed_out());
+                }
+                debug!("Replacing a pooled connection that failed to answer a ping");
+                drop(client);
+                state = self.lock();
+                state.open -= 1;
+                continue;
+            }
+            if state.open < self.options.max_connections {
+                state.open += 1;
+                drop(state);
+                return match self.open(deadline) {
+                    Ok(client) => Ok(self.checked_out(client)),
+                    Err(e) => {
+                        self.closed();
+                        Err(if Instant::now() >= deadline { timed_out() } else { e })
+                    }
+                };
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(timed_out());
+            }
+            state = self.available.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Returns how many connections are open, idle or checked out.
+    pub fn open_connections(&self) -> usize {
+        self.lock().open
+    }
+
+    /// Returns how many open connections wait in the pool.
+    pub fn idle_connections(&self) -> usize {
+        self.lock().idle.len()
+    }
+
+    fn open(&self, deadline: Instant) -> Result<KvsClient> {
+        KvsClient::connect_before(&self.addrs, &self.options.encodings, deadline)
+    }
+
+    fn checked_out(&self, client: KvsClient) -> PooledClient<'_> {
+        PooledClient {
+            pool: self,
+            client: Some(client),
+        }
+    }
+
+    /// Puts a connection back in the pool, or closes it if it is unusable.
+    fn put_back(&self, mut client: KvsClient) {
+        // the next user of the connection must not find itself in a transaction
+        let aborted = !client.in_transaction() || client.abort().is_ok();
+        if client.is_broken() || !aborted {
+            drop(client);
+            self.closed();
+            return;
+        }
+        self.lock().idle.push(client);
+        self.available.notify_one();
+    }
+
+    /// Accounts for a connection closed, which makes room for a new one.
+    fn closed(&self) {
+        self.lock().open -= 1;
+        self.available.notify_one();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// Pings a connection, waiting for the answer until `deadline` at most.
+///
+/// A connection that answers gets back the timeouts it had, which are none.
+fn ping_before(client: &mut KvsClient, deadline: Instant) -> bool {
+    let answered = time_left(deadline)
+        .and_then(|left| client.set_timeout(Some(left)))
+        .is_ok()
+        && client.ping().is_ok();
+    answered && client.set_timeout(None).is_ok()
+}
+
+fn timed_out() -> KvsError {
+    KvsError::Unavailable("Timed out waiting for a pooled connection".to_owned())
+}
+
+/// A connection checked out of a `KvsClientPool`, which goes back to the pool
+/// when dropped.
+///
+/// It derefs to `KvsClient`. A transaction left in progress is aborted when
+/// the connection goes back.
+pub struct PooledClient<'a> {
+    pool: &'a KvsClientPool,
+    // only taken when dropped
+    client: Option<KvsClient>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.put_back(client);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Length-delimited frames exchanged by `KvsClient` and `KvsServer` after the
+//! handshake.
+//!
+//! A frame is the length of its body as a big-endian `u32` followed by the
+//! body, a single message serialized with the encoding both sides agreed on.
+//! The length tells where a message ends without parsing it.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{KvsError, Result};
+
+/// Frames with a longer body are rejected before it is read.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// Encoding of the body of a frame.
+///
+/// It is picked during the handshake: the client lists the encodings it
+/// accepts in order of preference and the server answers with the first one
+/// it supports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// Compact and fast to encode, the default.
+    Bincode,
+    /// Readable when debugging.
+    Json,
+}
+
+impl Encoding {
+    /// Every encoding, in order of preference.
+    pub const ALL: &'static [Encoding] = &[Encoding::Bincode, Encoding::Json];
+
+    fn encode<T: Serialize>(self, msg: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Bincode => bincode::serialize(msg)?,
+            Encoding::Json => serde_json::to_vec(msg)?,
+        })
+    }
+
+    fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T> {
+        Ok(match self {
+            Encoding::Bincode => bincode::deserialize(body)?,
+            Encoding::Json => serde_json::from_slice(body)?,
+        })
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Encoding::Bincode => write!(f, "bincode"),
+            Encoding::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = KvsError;
+
+    fn from_str(name: &str) -> Result<Encoding> {
+        match name {
+            "bincode" => Ok(Encoding::Bincode),
+            "json" => Ok(Encoding::Json),
+            _ => Err(KvsError::StringError(format!("Unknown encoding {}", name))),
+        }
+    }
+}
+
+/// Reads and writes the frames of a connection.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Codec {
+    encoding: Encoding,
+}
+
+impl Codec {
+    pub(crate) fn new(encoding: Encoding) -> Codec {
+        Codec { encoding }
+    }
+
+    pub(crate) fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Writes `msg` as a frame. The writer is not flushed.
+    pub(crate) fn write<W: Write, T: Serialize>(&self, writer: &mut W, msg: &T) -> Result<()> {
+        let body = self.encoding.encode(msg)?;
+        let len = match u32::try_from(body.len()) {
+            Ok(len) if len <= MAX_FRAME_LEN => len,
+            _ => return Err(frame_too_large(body.len())),
+        };
+        writer.write_all(&len.to_be_bytes())?;
+        writer.write_all(&body)?;
+        Ok(())
+    }
+
+    /// Reads the next frame.
+    ///
+    /// Returns `None` if the stream ends where a frame would start. A stream
+    /// ending in the middle of a frame is an error.
+    pub(crate) fn read<R: Read, T: DeserializeOwned>(&self, reader: &mut R) -> Result<Option<T>> {
+        let mut len = [0; 4];
+        if let Err(e) = reader.read_exact(&mut len[..1]) {
+            return match e.kind() {
+                io::ErrorKind::UnexpectedEof => Ok(None),
+                _ => Err(e.into()),
+            };
+        }
+        reader.read_exact(&mut len[1..])?;
+        let mut body = vec![0; body_len(len)?];
+        reader.read_exact(&mut body)?;
+        self.encoding.decode(&body).map(Some)
+    }
+
+    /// Writes `msg` as a frame to an async writer. The writer is not flushed.
+    #[cfg(feature = "async")]
+    pub(crate) async fn write_async<W, T>(&self, writer: &mut W, msg: &T) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+        T: Serialize,
+    {
+        let mut frame = Vec::new();
+        self.write(&mut frame, msg)?;
+        writer.write_all(&frame).await?;
+        Ok(())
+    }
+
+    /// Reads the next frame from an async reader, like `read`.
+    #[cfg(feature = "async")]
+    pub(crate) async fn read_async<R, T>(&self, reader: &mut R) -> Result<Option<T>>
+    where
+        R: AsyncRead + Unpin,
+        T: DeserializeOwned,
+    {
+        let mut len = [0; 4];
+        if let Err(e) = reader.read_exact(&mut len[..1]).await {
+            return match e.kind() {
+                io::ErrorKind::UnexpectedEof => Ok(None),
+                _ => Err(e.into()),
+            };
+        }
+        reader.read_exact(&mut len[1..]).await?;
+        let mut body = vec![0; body_len(len)?];
+        reader.read_exact(&mut body).await?;
+        self.encoding.decode(&body).map(Some)
+    }
+}
+
+// The length of a body from the header of its frame, which must be within the limit.
+fn body_len(header: [u8; 4]) -> Result<usize> {
+    let len = u32::from_be_bytes(header);
+    if len > MAX_FRAME_LEN {
+        return Err(frame_too_large(len as usize));
+    }
+    Ok(len as usize)
+}
+
+fn frame_too_large(len: usize) -> KvsError {
+    KvsError::StringError(format!(
+        "Frame of {} bytes exceeds the limit of {} bytes",
+        len, MAX_FRAME_LEN
+    ))
+}
@@ -1,28 +1,189 @@
+use std::io::{BufRead, Read};
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use crate::engines::WriteBatch;
+use crate::error::{ErrorCode, KvsError};
+
+
+/// Version of the protocol spoken by this crate.
+///
+/// It is bumped whenever the messages following the handshake change incompatibly.
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// Optional features of the protocol supported by this crate.
+pub const FEATURES: &[&str] = &["ttl", "batch", "cas", "transactions", "ping"];
+
+/// Handshakes longer than this are rejected rather than read to the end.
+pub const MAX_HANDSHAKE_LEN: usize = 64 * 1024;
 
 
+/// The first message sent by each side when a connection opens.
+///
+/// Its encoding never changes, so peers speaking different versions of the
+/// protocol can still tell each other apart. The server answers with its own
+/// version and the features both sides support, then closes the connection if
+/// the versions differ or no encoding suits both sides.
+///
+/// It is plain JSON. The messages that follow it are framed by the codec.
 #[derive(Debug, Serialize, Deserialize)]
-pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+pub struct Handshake {
+    pub version: u32,
+    pub features: Vec<String>,
+    // encodings the client accepts in order of preference, or the one the server picked
+    #[serde(default)]
+    pub encodings: Vec<String>,
+    // why the server turns the connection down, such as too many connections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Handshake {
+    /// Reads a handshake, leaving the bytes after it in the buffer of the reader.
+    pub(crate) fn read<R: BufRead>(reader: &mut R) -> crate::Result<Handshake> {
+        let mut limited = reader.take(MAX_HANDSHAKE_LEN as u64);
+        match Handshake::deserialize(&mut Deserializer::from_reader(&mut limited)) {
+            Ok(handshake) => Ok(handshake),
+            Err(e) if e.is_eof() && limited.limit() == 0 => Err(handshake_too_long()),
+            Err(e) => Err(e.into()),
+        }
+    }
 }
 
+pub(crate) fn handshake_too_long() -> KvsError {
+    KvsError::StringError(format!(
+        "Handshake exceeds the limit of {} bytes",
+        MAX_HANDSHAKE_LEN
+    ))
+}
+
+/// Finds the end of a handshake arriving in pieces, looking at every byte once.
+///
+/// Parsing the bytes received so far whenever more arrive would take time
+/// quadratic in the length of the handshake.
+#[derive(Default)]
+pub(crate) struct HandshakeScanner {
+    // how many bytes have been looked at
+    scanned: usize,
+    // how deep in objects and arrays the scanned bytes end
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
 
+impl HandshakeScanner {
+    /// Returns the length of the handshake at the start of `input` if it has
+    /// arrived in full, where `input` starts with the bytes given before.
+    ///
+    /// A handshake that is not a JSON object ends at its first byte, for the
+    /// parser to report.
+    pub(crate) fn scan(&mut self, input: &[u8]) -> crate::Result<Option<usize>> {
+        let input = &input[..input.len().min(MAX_HANDSHAKE_LEN)];
+        for (i, &byte) in input.iter().enumerate().skip(self.scanned) {
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' if self.depth > 1 => self.depth -= 1,
+                b' ' | b'\t' | b'\n' | b'\r' => {}
+                _ if self.depth == 0 || matches!(byte, b'}' | b']') => return Ok(Some(i + 1)),
+                _ => {}
+            }
+        }
+        self.scanned = input.len();
+        if self.scanned == MAX_HANDSHAKE_LEN {
+            return Err(handshake_too_long());
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(feature = "async")]
+impl Handshake {
+    /// Reads a handshake from an async reader, leaving the bytes after it in
+    /// the buffer of the reader.
+    pub(crate) async fn read_async<R: AsyncBufRead + Unpin>(
+        reader: &mut R,
+    ) -> crate::Result<Handshake> {
+        // the bytes consumed from the reader so far
+        let mut read = Vec::new();
+        let mut scanner = HandshakeScanner::default();
+        loop {
+            let buf = reader.fill_buf().await?;
+            if buf.is_empty() {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            let len = read.len();
+            read.extend_from_slice(buf);
+            match scanner.scan(&read)? {
+                Some(end) => {
+                    reader.consume(end - len);
+                    return Ok(serde_json::from_slice(&read[..end])?);
+                }
+                None => reader.consume(read.len() - len),
+            }
+        }
+    }
+}
+
+
+/// A message with the id of the request it belongs to.
+///
+/// The response to a request carries the id of the request back, so a client
+/// can send many requests before reading their responses.
 #[derive(Debug, Serialize, Deserialize)]
-pub enum GetResponse {
-    Ok(Option<String>),
-    Err(String)
+pub struct Tagged<T> {
+    pub id: u64,
+    pub msg: T,
 }
 
+
 #[derive(Debug, Serialize, Deserialize)]
-pub enum SetResponse {
-    Ok,
-    Err(String)
+pub enum Request {
+    Get { key: Vec<u8> },
+    Set { key: Vec<u8>, value: Vec<u8>, ttl: Option<Duration> },
+    Remove { key: Vec<u8> },
+    Batch { batch: WriteBatch },
+    CompareAndSwap { key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>> },
+    // Get, Set and Remove requests between Begin and Commit or Abort are part
+    // of the transaction of the connection.
+    Begin,
+    Commit,
+    Abort,
+    /// Answered with Ok, to check that the connection works.
+    Ping,
 }
 
+
+/// The answer of the server to any request.
 #[derive(Debug, Serialize, Deserialize)]
-pub enum RemoveResponse {
+pub enum Response {
+    /// The request succeeded without returning anything.
     Ok,
-    Err(String)
+    /// The value of a key, `None` if it does not exist.
+    Value(Option<Vec<u8>>),
+    /// Whether a compare-and-swap took place.
+    Swapped(bool),
+    /// The transaction was not committed because of a conflicting write.
+    Conflict,
+    /// The request failed.
+    Err { code: ErrorCode, message: String },
+}
+
+impl From<KvsError> for Response {
+    fn from(err: KvsError) -> Response {
+        Response::Err { code: err.code(), message: err.to_string() }
+    }
 }
\ No newline at end of file
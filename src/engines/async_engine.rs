@@ -0,0 +1,136 @@
+use std::future::Future;
+use std::ops::Bound;
+use std::panic;
+use std::time::Duration;
+
+use super::{KvsEngine, WriteBatch};
+use crate::Result;
+
+/// A `KvsEngine` for async code.
+///
+/// Engine calls block on disk and on locks, so each call runs on the blocking
+/// threads of the tokio runtime rather than on the thread of the task. The
+/// returned futures do not borrow the engine, and it is cheap to clone, like
+/// the engine it wraps.
+#[derive(Clone)]
+pub struct AsyncEngine<E: KvsEngine> {
+    engine: E,
+}
+
+impl<E: KvsEngine> AsyncEngine<E> {
+    pub fn new(engine: E) -> Self {
+        AsyncEngine { engine }
+    }
+
+    /// Returns the engine, to call it where blocking is fine.
+    pub fn get_ref(&self) -> &E {
+        &self.engine
+    }
+
+    /// Runs `f` with the engine on a blocking thread and returns its result.
+    ///
+    /// It is meant for what the other methods do not cover, such as a
+    /// transaction made of several calls.
+    pub fn run<F, R>(&self, f: F) -> impl Future<Output = Result<R>>
+    where
+        F: FnOnce(&E) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let engine = self.engine.clone();
+        async move {
+            match tokio::task::spawn_blocking(move || f(&engine)).await {
+                Ok(result) => result,
+                Err(e) => panic::resume_unwind(e.into_panic()),
+            }
+        }
+    }
+
+    pub fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> {
+        self.run(move |engine| engine.get(key))
+    }
+
+    pub fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.set(key, value))
+    }
+
+    pub fn set_with_ttl(
+        &self,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.set_with_ttl(key, value, ttl))
+    }
+
+    pub fn remove(&self, key: String) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.remove(key))
+    }
+
+    pub fn get_bytes(&self, key: Vec<u8>) -> impl Future<Output = Result<Option<Vec<u8>>>> {
+        self.run(move |engine| engine.get_bytes(key))
+    }
+
+    pub fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.set_bytes(key, value))
+    }
+
+    pub fn set_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.set_bytes_with_ttl(key, value, ttl))
+    }
+
+    pub fn remove_bytes(&self, key: Vec<u8>) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.remove_bytes(key))
+    }
+
+    /// Returns the string key/value pairs with keys in the range, in key order.
+    ///
+    /// Unlike `KvsEngine::scan`, the pairs are read up front.
+    pub fn scan(
+        &self,
+        range: (Bound<String>, Bound<String>),
+        limit: Option<usize>,
+    ) -> impl Future<Output = Result<Vec<(String, String)>>> {
+        self.run(move |engine| engine.scan(range, limit)?.collect())
+    }
+
+    /// Returns the string key/value pairs with keys starting with `prefix`, in
+    /// key order. The pairs are read up front.
+    pub fn scan_prefix(
+        &self,
+        prefix: String,
+        limit: Option<usize>,
+    ) -> impl Future<Output = Result<Vec<(String, String)>>> {
+        self.run(move |engine| engine.scan_prefix(prefix, limit)?.collect())
+    }
+
+    pub fn write(&self, batch: WriteBatch) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.write(batch))
+    }
+
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> impl Future<Output = Result<bool>> {
+        self.run(move |engine| engine.compare_and_swap(key, expected, new))
+    }
+
+    pub fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> impl Future<Output = Result<bool>> {
+        self.run(move |engine| engine.compare_and_swap_bytes(key, expected, new))
+    }
+
+    pub fn flush(&self) -> impl Future<Output = Result<()>> {
+        self.run(|engine| engine.flush())
+    }
+}
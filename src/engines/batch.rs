@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::expiry::expiry_time;
+
+/// A group of writes applied atomically by `KvsEngine::write`.
+///
+/// The operations are applied in the order they were added, so a later
+/// operation on a key overrides an earlier one. Keys and values are bytes,
+/// and anything convertible to `Vec<u8>` such as a `String` can be passed.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::{KvsEngine, WriteBatch};
+/// let store = KvStore::open(current_dir()?)?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("from".to_owned(), "90".to_owned());
+/// batch.set("to".to_owned(), "110".to_owned());
+/// batch.remove("pending".to_owned());
+/// store.write(batch)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A single operation of a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// Sets the value of a key.
+    Set {
+        /// The key to set.
+        key: Vec<u8>,
+        /// The new value.
+        value: Vec<u8>,
+    },
+    /// Removes a key.
+    Remove {
+        /// The key to remove.
+        key: Vec<u8>,
+    },
+    /// Sets the value of a key that expires at a point in time.
+    SetWithExpiry {
+        /// The key to set.
+        key: Vec<u8>,
+        /// The new value.
+        value: Vec<u8>,
+        /// When the key expires, in milliseconds since the Unix epoch.
+        expires_at: u64,
+    },
+}
+
+impl BatchOp {
+    /// Returns the key the operation applies to.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Set { key, .. }
+            | BatchOp::Remove { key }
+            | BatchOp::SetWithExpiry { key, .. } => key,
+        }
+    }
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds setting the value of `key` to `value`.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Adds setting the value of `key` to `value` that expires after `ttl`.
+    ///
+    /// The TTL starts when the operation is added, not when the batch is
+    /// applied.
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> &mut WriteBatch {
+        self.set_expiring_at(key, value, expiry_time(ttl))
+    }
+
+    /// Adds setting the value of `key` to `value` that expires at
+    /// `expires_at`, in milliseconds since the Unix epoch.
+    pub(crate) fn set_expiring_at(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        expires_at: u64,
+    ) -> &mut WriteBatch {
+        self.ops.push(BatchOp::SetWithExpiry {
+            key: key.into(),
+            value: value.into(),
+            expires_at,
+        });
+        self
+    }
+
+    /// Adds removing `key`.
+    ///
+    /// Unlike `KvsEngine::remove`, removing a key that does not exist is not an
+    /// error and does not fail the batch.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+        self
+    }
+
+    /// Returns the number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns whether the batch has no operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns the operations in the order they are applied.
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// Consumes the batch and returns its operations.
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
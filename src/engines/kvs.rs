@@ -3,25 +3,46 @@ use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::ops::{Range, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
 use crossbeam_skiplist::SkipMap;
-use log::error;
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
 
-use super::KvsEngine;
+use self::compaction::{CompactionTask, Compactor, Message};
+use self::hint::load_hint;
+use self::record::{
+    is_torn, read_file_header, read_record, write_file_header, write_record, FILE_HEADER_LEN,
+    LOG_MAGIC,
+};
+use self::scan::KvStoreScan;
+use self::snapshot::{History, Pins};
+use super::expiry::{expiry_time, now_millis};
+use super::sync::Syncer;
+use super::{BatchOp, ByteScan, KvsEngine, SyncPolicy, WriteBatch};
 use crate::{KvsError, Result};
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+pub use self::options::KvStoreOptions;
+pub use self::snapshot::KvStoreSnapshot;
 
-/// The `KvStore` stores string key/value pairs.
+mod compaction;
+mod hint;
+mod options;
+mod record;
+mod scan;
+mod snapshot;
+
+/// The `KvStore` stores key/value pairs of arbitrary bytes.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
+/// Each command is stored as a length-prefixed, checksummed binary record so
+/// damaged or partially written records are detected instead of misread.
 /// A skip list in memory stores the keys and the value locations for fast query.
 ///
 /// ```rust
@@ -39,9 +60,16 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 #[derive(Clone)]
 pub struct KvStore {
     // map generation number to the file reader
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    // entries of the index that the live snapshots may still read
+    history: Arc<History>,
     reader: KvStoreReader,
-    writer: Arc<Mutex<KvStoreWriter>>,
+    // `None` if the store is opened read-only
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    // fsyncs the active log periodically with `SyncPolicy::EveryNms`
+    _syncer: Option<Arc<Syncer>>,
+    // rewrites sealed logs in the background
+    _compactor: Option<Arc<Compactor>>,
 }
 
 impl KvStore {
@@ -52,85 +80,363 @@ impl KvStore {
     /// # Errors
     ///
     /// It propagates I/O or deserialization errors during the log replay.
+    /// A damaged log record is reported as `KvsError::Corruption`.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// A partially written record at the end of the newest log file is what a
+    /// crash in the middle of a write leaves behind. It is truncated away and the
+    /// store opens with every record before it.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    /// Any other damaged record, such as one failing its checksum, is reported as
+    /// `KvsError::Corruption` unless `KvStoreOptions::recover_sealed_logs` is enabled.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let path = Arc::new(path.into());
-        fs::create_dir_all(&*path)?;
+        if options.create_if_missing && !options.read_only {
+            fs::create_dir_all(&*path)?;
+        }
 
         let mut readers = BTreeMap::new();
         let index = Arc::new(SkipMap::new());
 
         let gen_list = sorted_gen_list(&path)?;
         let mut uncompacted = 0;
+        let mut batches = BatchRefs::default();
 
-        for &gen in &gen_list {
+        for (i, &gen) in gen_list.iter().enumerate() {
             let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
+            // Logs written by a compaction come with a hint file, which is much
+            // cheaper to load than replaying the log.
+            if let Some(hint_uncompacted) = load_hint(&path, gen, &*index, &mut batches)? {
+                uncompacted += hint_uncompacted;
+                readers.insert(gen, reader);
+                continue;
+            }
+            let replay = load(gen, &mut reader, &*index, &mut batches)?;
+            uncompacted += replay.uncompacted;
+
+            let torn = replay.corruption.as_ref().is_some_and(is_torn);
+            match replay.corruption {
+                None => {}
+                // The newest generation is the only one being written to, so a
+                // record cut short at its end is a torn write. A damaged record
+                // followed by more data is not, and is handled like in any other log.
+                Some(KvsError::Corruption { pos, reason, .. }) if torn && i + 1 == gen_list.len() => {
+                    if options.read_only {
+                        warn!(
+                            "Ignoring a torn write in {:?} at offset {}: {}",
+                            log_path(&path, gen),
+                            pos,
+                            reason
+                        );
+                        if pos < FILE_HEADER_LEN {
+                            continue;
+                        }
+                    } else if !truncate_torn_log(&path, gen, pos, &reason)? {
+                        // nothing valid is left in the file
+                        continue;
+                    }
+                }
+                Some(KvsError::Corruption { pos, reason, .. }) if options.recover_sealed_logs => {
+                    warn!(
+                        "Ignoring {:?} from offset {}: {}",
+                        log_path(&path, gen),
+                        pos,
+                        reason
+                    );
+                }
+                Some(e) => return Err(e),
+            }
             readers.insert(gen, reader);
         }
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen)?;
         let safe_point = Arc::new(AtomicU64::new(0));
-
         let reader = KvStoreReader {
             path: Arc::clone(&path),
             safe_point,
             readers: RefCell::new(readers),
         };
 
-        let writer = KvStoreWriter {
-            reader: reader.clone(),
-            writer,
+        let history = Arc::new(History::new());
+        if options.read_only {
+            return Ok(KvStore {
+                reader,
+                index,
+                history,
+                writer: None,
+                _syncer: None,
+                _compactor: None,
+            });
+        }
+
+        remove_unfinished_files(&path)?;
+
+        let (sender, receiver) = mpsc::channel();
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
+            writer: new_log_file(&path, current_gen)?,
             current_gen,
             uncompacted,
+            batches,
+            compacting: false,
+            compactor: sender.clone(),
             path: Arc::clone(&path),
             index: Arc::clone(&index),
+            seq: 0,
+            history: Arc::clone(&history),
+            pins: Pins::default(),
+            sync_policy: options.sync_policy,
+            compaction_threshold: options.compaction_threshold,
+            max_log_file_size: options.max_log_file_size,
+        }));
+
+        let compactor = Compactor::spawn(
+            sender,
+            receiver,
+            CompactionTask {
+                path: Arc::clone(&path),
+                index: Arc::clone(&index),
+                reader: reader.clone(),
+                writer: Arc::clone(&writer),
+                stopping: Arc::new(AtomicBool::new(false)),
+            },
+        );
+
+        let syncer = match options.sync_policy {
+            SyncPolicy::EveryNms(ms) => {
+                let writer = Arc::clone(&writer);
+                Some(Arc::new(Syncer::spawn(ms, move || {
+                    writer.lock().unwrap().sync()
+                })))
+            }
+            _ => None,
         };
 
         Ok(KvStore {
             reader,
             index,
-            writer: Arc::new(Mutex::new(writer)),
+            history,
+            writer: Some(writer),
+            _syncer: syncer,
+            _compactor: Some(Arc::new(compactor)),
         })
     }
+
+    fn writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        match self.writer {
+            Some(ref writer) => Ok(writer.lock().unwrap()),
+            None => Err(KvsError::ReadOnly),
+        }
+    }
+
+    // Read the current value of `key`, treating an expired entry as missing.
+    fn read_live_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        loop {
+            let Some(cmd_pos) = live_pos(&self.index, key) else {
+                return Ok(None);
+            };
+            match self.reader.read_value(key, cmd_pos) {
+                // the new position of the value is in the index by now
+                Err(e) if self.reader.is_moved(&e, cmd_pos) => continue,
+                result => return result.map(Some),
+            }
+        }
+    }
 }
 
 impl KvsEngine for KvStore {
-    /// Sets the value of a string key to a string.
+    type Snapshot = KvStoreSnapshot;
+
+    /// Sets the value of a key.
     ///
     /// If the key already exists, the previous value will be overwritten.
     ///
     /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.writer()?.set(key, value, None)
     }
 
-    /// Gets the string value of a given string key.
+    /// Sets the value of a key that expires after `ttl`.
     ///
-    /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            if let Command::Set { value, .. } = self.reader.read_command(*cmd_pos.value())? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
-            }
-        } else {
-            Ok(None)
+    /// The expiry time is stored in the log so it holds across restarts. Expired
+    /// entries are dropped from the logs by the next compaction.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.writer()?.set(key, value, Some(expiry_time(ttl)))
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist or has expired.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.read_live_value(&key)? {
+            return Ok(Some(value));
         }
+        // Overwriting a key in the index removes the old entry before inserting
+        // the new one, so a miss is confirmed under the writer lock, which every
+        // update of the index holds.
+        let _writer = self.writer.as_ref().map(|writer| writer.lock().unwrap());
+        self.read_live_value(&key)
     }
 
     /// Removes a given key.
     ///
     /// # Error
     ///
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    /// It returns `KvsError::KeyNotFound` if the given key is not found or has expired.
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.writer()?.remove(key)
+    }
+
+    /// Returns the key/value pairs with keys in `range`, in key order.
+    ///
+    /// Values are read lazily as the iterator advances, and writes made in the
+    /// meantime may or may not be visible to it.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Result<ByteScan> {
+        Ok(Box::new(KvStoreScan::new(
+            Arc::clone(&self.index),
+            self.reader.clone(),
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+            limit,
+        )))
+    }
+
+    /// Returns the key/value pairs with keys starting with `prefix`, in key order.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<ByteScan> {
+        Ok(Box::new(KvStoreScan::with_prefix(
+            Arc::clone(&self.index),
+            self.reader.clone(),
+            prefix,
+            limit,
+        )))
+    }
+
+    /// Applies all the operations of `batch` or none of them.
+    ///
+    /// The batch is written to the log as a single record, so a crash leaves
+    /// either all of it or none of it on disk. Concurrent readers may observe
+    /// the operations being applied one by one.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.writer()?.write(batch)
+    }
+
+    /// Sets `key` to `new` if its current value is `expected`, atomically.
+    ///
+    /// The current value is checked while holding the writer lock, so no other
+    /// write can slip in between the check and the swap.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the log.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let mut writer = self.writer()?;
+        let current = self.read_live_value(&key)?;
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => writer.set(key, value, None)?,
+            None if current.is_some() => writer.remove(key)?,
+            // the key is already missing
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// Sets `key` to `value`, expiring after `ttl` or when it would have, if
+    /// its current value is `expected`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the log.
+    fn compare_and_swap_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<bool> {
+        let mut writer = self.writer()?;
+        if self.read_live_value(&key)? != expected {
+            return Ok(false);
+        }
+        let expires_at = match ttl {
+            Some(ttl) => Some(expiry_time(ttl)),
+            None => live_pos(&self.index, &key).and_then(|cmd_pos| cmd_pos.expires_at),
+        };
+        writer.set(key, value, expires_at)?;
+        Ok(true)
+    }
+
+    /// Applies `batch` if every key in `expected` still has the given value.
+    ///
+    /// The values are checked while holding the writer lock, like
+    /// `compare_and_swap_bytes` does.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the log.
+    fn write_if(
+        &self,
+        expected: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<bool> {
+        let mut writer = self.writer()?;
+        for (key, value) in expected {
+            if self.read_live_value(&key)? != value {
+                return Ok(false);
+            }
+        }
+        if !batch.is_empty() {
+            writer.write(batch)?;
+        }
+        Ok(true)
+    }
+
+    /// Returns a read-only view of the store as of now.
+    ///
+    /// Taking a snapshot is cheap. While it is alive, overwritten index entries
+    /// are kept in memory and stale logs are kept on disk for it.
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        Ok(KvStoreSnapshot::new(self))
+    }
+
+    /// Syncs the active log file. A store opened read-only has nothing to sync.
+    fn flush(&self) -> Result<()> {
+        match self.writer {
+            Some(ref writer) => writer.lock().unwrap().sync(),
+            None => Ok(()),
+        }
     }
 }
 
@@ -165,6 +471,16 @@ impl KvStoreReader {
         }
     }
 
+    /// Returns a reader for a snapshot, which keeps the handles of the logs
+    /// from generation `first_gen` on after a compaction made them stale.
+    fn pinned(&self, first_gen: u64) -> KvStoreReader {
+        KvStoreReader {
+            path: Arc::clone(&self.path),
+            safe_point: Arc::new(AtomicU64::new(first_gen)),
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+
     /// Read the log file at the given `CommandPos`.
     fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
     where
@@ -185,10 +501,39 @@ impl KvStoreReader {
         f(cmd_reader)
     }
 
-    // Read the log file at the given `CommandPos` and deserialize it to `Command`.
+    /// Tells whether reading `cmd_pos` failed with `err` because a compaction
+    /// removed its log after the position was looked up in the index.
+    fn is_moved(&self, err: &KvsError, cmd_pos: CommandPos) -> bool {
+        matches!(err, KvsError::Io(e) if e.kind() == io::ErrorKind::NotFound)
+            && cmd_pos.gen < self.safe_point.load(Ordering::SeqCst)
+    }
+
+    // Read the record at the given `CommandPos` and return the value it sets for `key`.
+    fn read_value(&self, key: &[u8], cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        match self.read_command(cmd_pos)? {
+            Command::Set { value, .. } | Command::SetWithExpiry { value, .. } => Ok(value),
+            // the index points at a batch only if its last operation on the key is a set
+            Command::Batch { ops } => match ops.into_iter().rev().find(|op| op.key() == key) {
+                Some(BatchOp::Set { value, .. } | BatchOp::SetWithExpiry { value, .. }) => {
+                    Ok(value)
+                }
+                _ => Err(KvsError::UnexpectedCommandType),
+            },
+            Command::Remove { .. } => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    // Read the record at the given `CommandPos` and deserialize it to `Command`.
     fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
-        self.read_and(cmd_pos, |cmd_reader| {
-            Ok(serde_json::from_reader(cmd_reader)?)
+        self.read_and(cmd_pos, |mut cmd_reader| {
+            match read_record(&mut cmd_reader, cmd_pos.gen, cmd_pos.pos)? {
+                Some((cmd, _)) => Ok(cmd),
+                None => Err(KvsError::Corruption {
+                    gen: cmd_pos.gen,
+                    pos: cmd_pos.pos,
+                    reason: "missing record".to_owned(),
+                }),
+            }
         })
     }
 }
@@ -205,123 +550,198 @@ impl Clone for KvStoreReader {
 }
 
 struct KvStoreWriter {
-    reader: KvStoreReader,
     writer: BufWriterWithPos<File>,
     current_gen: u64,
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction
     uncompacted: u64,
+    batches: BatchRefs,
+    // whether the compaction thread is working on the sealed logs
+    compacting: bool,
+    compactor: Sender<Message>,
     path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    // sequence number of the last write
+    seq: u64,
+    history: Arc<History>,
+    pins: Pins,
+    sync_policy: SyncPolicy,
+    compaction_threshold: u64,
+    max_log_file_size: Option<u64>,
 }
 
 impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let cmd = Command::set(key, value, expires_at);
         let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
+        write_record(&mut self.writer, &cmd)?;
+        self.commit()?;
+        if let Command::Set { key, .. } | Command::SetWithExpiry { key, .. } = cmd {
             if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
+                self.uncompacted += self.batches.release(*old_cmd.value());
             }
+            let seq = self.next_seq();
+            self.preserve(&key, seq);
+            let cmd_pos = CommandPos::from((self.current_gen, pos..self.writer.pos));
             self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
+                .insert(key, cmd_pos.expiring_at(expires_at).at_seq(seq));
         }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
-        }
-        Ok(())
+        self.maintain()
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        if live_pos(&self.index, &key).is_some() {
             let cmd = Command::remove(key);
             let pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
+            write_record(&mut self.writer, &cmd)?;
+            self.commit()?;
             if let Command::Remove { key } = cmd {
+                let seq = self.next_seq();
+                self.preserve(&key, seq);
                 let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.value().len;
+                self.uncompacted += self.batches.release(*old_cmd.value());
                 // the "remove" command itself can be deleted in the next compaction
                 // so we add its length to `uncompacted`
                 self.uncompacted += self.writer.pos - pos;
             }
 
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
-            }
-            Ok(())
+            self.maintain()
         } else {
             Err(KvsError::KeyNotFound)
         }
     }
 
+    fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        let cmd = Command::batch(batch);
+        let pos = self.writer.pos;
+        write_record(&mut self.writer, &cmd)?;
+        self.commit()?;
+        if let Command::Batch { ops } = cmd {
+            let seq = self.next_seq();
+            for op in &ops {
+                self.preserve(op.key(), seq);
+            }
+            let cmd_pos = CommandPos::from((self.current_gen, pos..self.writer.pos));
+            let cmd_pos = cmd_pos.at_seq(seq);
+            self.uncompacted += apply_batch(&self.index, &mut self.batches, ops, cmd_pos);
+        }
+
+        self.maintain()
+    }
+
+    /// Returns the sequence number of a new write.
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    /// Saves the index entry of `key` to the history before the write with
+    /// sequence number `seq` changes it, if a snapshot may need it.
+    fn preserve(&self, key: &[u8], seq: u64) {
+        if !self.pins.is_empty() {
+            let old_pos = self.index.get(key).map(|entry| *entry.value());
+            self.history.insert((key.to_vec(), seq), old_pos);
+        }
+    }
+
+    /// Hands the written records to the OS and fsyncs them if the sync policy
+    /// requires it before the write is acknowledged.
+    fn commit(&mut self) -> Result<()> {
+        if self.sync_policy == SyncPolicy::Always {
+            self.sync()
+        } else {
+            Ok(self.writer.flush()?)
+        }
+    }
+
+    /// Compacts the logs or moves on to a new log file after a write if needed.
+    fn maintain(&mut self) -> Result<()> {
+        if self.uncompacted > self.compaction_threshold && !self.compacting {
+            self.compact()
+        } else if self.max_log_file_size.is_some_and(|max| self.writer.pos >= max) {
+            self.roll_over()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Seals the active log file and starts writing to a new one.
+    fn roll_over(&mut self) -> Result<()> {
+        if self.sync_policy != SyncPolicy::Never {
+            self.sync()?;
+        }
+        self.current_gen += 1;
+        self.writer = new_log_file(&self.path, self.current_gen)?;
+        Ok(())
+    }
+
+    /// Forces the active log to disk.
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
     /// Clears stale entries in the log.
+    ///
+    /// The active log is sealed and the compaction thread is asked to rewrite
+    /// the sealed logs, while new writes go on in a new generation.
     fn compact(&mut self) -> Result<()> {
         // increase current gen by 2. current_gen + 1 is for the compaction file
         let compaction_gen = self.current_gen + 1;
+        if self.sync_policy != SyncPolicy::Never {
+            self.sync()?;
+        }
         self.current_gen += 2;
         self.writer = new_log_file(&self.path, self.current_gen)?;
 
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
-
-        let mut new_pos = 0; // pos in the new log file
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
-            })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
-            new_pos += len;
-        }
-        compaction_writer.flush()?;
-
-        self.reader
-            .safe_point
-            .store(compaction_gen, Ordering::SeqCst);
-        self.reader.close_stale_handles();
-
-        // remove stale log files
-        // Note that actually these files are not deleted immediately because `KvStoreReader`s
-        // still keep open file handles. When `KvStoreReader` is used next time, it will clear
-        // its stale file handles. On Unix, the files will be deleted after all the handles
-        // are closed. On Windows, the deletions below will fail and stale files are expected
-        // to be deleted in the next compaction.
-
-        let stale_gens = sorted_gen_list(&self.path)?
-            .into_iter()
-            .filter(|&gen| gen < compaction_gen);
-        for stale_gen in stale_gens {
-            let file_path = log_path(&self.path, stale_gen);
-            if let Err(e) = fs::remove_file(&file_path) {
-                error!("{:?} cannot be deleted: {}", file_path, e);
-            }
+        self.compacting = true;
+        // the count goes down only once the compaction has reclaimed the bytes
+        let stale = self.uncompacted;
+        if self.compactor.send(Message::Compact(compaction_gen, stale)).is_err() {
+            self.compacting = false;
+            error!("Compaction thread is gone");
         }
-        self.uncompacted = 0;
-
         Ok(())
     }
 }
 
 /// Create a new log file with given generation number and add the reader to the readers map.
 ///
+/// The file header is written right away so the log is valid even if it stays empty.
+///
 /// Returns the writer to the log.
 fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
     let path = log_path(path, gen);
-    let writer = BufWriterWithPos::new(
+    let mut writer = BufWriterWithPos::new(
         OpenOptions::new()
             .create(true)
             .write(true)
             .append(true)
             .open(&path)?,
     )?;
+    write_file_header(&mut writer, &LOG_MAGIC)?;
+    writer.flush()?;
     Ok(writer)
 }
 
+/// Removes the output of compactions interrupted by a crash.
+///
+/// A compaction renames its output log and hint file only once they are
+/// complete, so the logs it was copying from are still intact.
+fn remove_unfinished_files(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let file_path = entry?.path();
+        let extension = file_path.extension();
+        if extension == Some("compacting".as_ref()) || extension == Some("tmp".as_ref()) {
+            warn!("Removing unfinished file {:?}", file_path);
+            fs::remove_file(&file_path)?;
+        }
+    }
+    Ok(())
+}
+
 /// Returns sorted generation numbers in the given directory
 fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     let mut gen_list: Vec<u64> = fs::read_dir(&path)?
@@ -339,39 +759,204 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     Ok(gen_list)
 }
 
-/// Load the whole log file and store value locations in the index map.
+/// Truncates the log file of generation `gen` at `pos`, dropping a torn tail.
+///
+/// A file with a torn header holds no records at all, so it is removed instead.
+///
+/// Returns whether the log file still exists.
+fn truncate_torn_log(dir: &Path, gen: u64, pos: u64, reason: &str) -> Result<bool> {
+    let path = log_path(dir, gen);
+    let file = OpenOptions::new().write(true).open(&path)?;
+    let len = file.metadata()?.len();
+    if pos < FILE_HEADER_LEN {
+        drop(file);
+        fs::remove_file(&path)?;
+        warn!("Removed {:?} with a partially written header", path);
+        return Ok(false);
+    }
+    file.set_len(pos)?;
+    file.sync_all()?;
+    warn!(
+        "Discarded {} bytes at the end of {:?} ({})",
+        len - pos,
+        path,
+        reason
+    );
+    Ok(true)
+}
+
+/// Outcome of replaying a single log file.
+struct Replay {
+    /// number of bytes that can be saved after a compaction
+    uncompacted: u64,
+    /// the corruption that stopped the replay, if any
+    corruption: Option<KvsError>,
+}
+
+/// Load the log file and store value locations in the index map.
 ///
-/// Returns how many bytes can be saved after a compaction.
+/// The replay stops at the first damaged record. Every record before it is
+/// applied to the index and the error is returned in `Replay::corruption`.
 fn load(
     gen: u64,
     reader: &mut BufReaderWithPos<File>,
-    index: &SkipMap<String, CommandPos>,
-) -> Result<u64> {
-    // To make sure we read from the beginning of the file
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    batches: &mut BatchRefs,
+) -> Result<Replay> {
     let mut uncompacted = 0; // number of bytes that can be saved after a compaction
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
+    macro_rules! try_record {
+        ($expr:expr) => {
+            match $expr {
+                Ok(value) => value,
+                Err(e @ KvsError::Corruption { .. }) => {
+                    return Ok(Replay {
+                        uncompacted,
+                        corruption: Some(e),
+                    })
+                }
+                Err(e) => return Err(e),
+            }
+        };
+    }
+
+    // To make sure we read from the beginning of the file
+    reader.seek(SeekFrom::Start(0))?;
+    try_record!(read_file_header(reader, gen, &LOG_MAGIC));
+    let mut pos = reader.pos;
+    while let Some((cmd, len)) = try_record!(read_record(reader, gen, pos)) {
+        let new_pos = pos + len;
+        match cmd {
             Command::Set { key, .. } => {
                 if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().len;
+                    uncompacted += batches.release(*old_cmd.value());
                 }
                 index.insert(key, (gen, pos..new_pos).into());
             }
+            Command::SetWithExpiry {
+                key, expires_at, ..
+            } => {
+                if let Some(old_cmd) = index.get(&key) {
+                    uncompacted += batches.release(*old_cmd.value());
+                }
+                let cmd_pos = CommandPos::from((gen, pos..new_pos));
+                index.insert(key, cmd_pos.expiring_at(Some(expires_at)));
+            }
             Command::Remove { key } => {
                 if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
+                    uncompacted += batches.release(*old_cmd.value());
                 }
                 // the "remove" command itself can be deleted in the next compaction
                 // so we add its length to `uncompacted`
                 uncompacted += new_pos - pos;
             }
+            Command::Batch { ops } => {
+                uncompacted += apply_batch(index, batches, ops, (gen, pos..new_pos).into());
+            }
         }
         pos = new_pos;
     }
-    Ok(uncompacted)
+    Ok(Replay {
+        uncompacted,
+        corruption: None,
+    })
+}
+
+/// Points the index at the batch record at `cmd_pos` for every key it sets and
+/// drops the keys it removes.
+///
+/// Returns the number of bytes that can be saved after a compaction.
+fn apply_batch(
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    batches: &mut BatchRefs,
+    ops: Vec<BatchOp>,
+    cmd_pos: CommandPos,
+) -> u64 {
+    // only the last operation on each key has an effect, which is a set with
+    // the expiry time of the key or a remove
+    let mut last_ops = BTreeMap::new();
+    for op in ops {
+        match op {
+            BatchOp::Set { key, .. } => last_ops.insert(key, Some(None)),
+            BatchOp::Remove { key } => last_ops.insert(key, None),
+            BatchOp::SetWithExpiry { key, expires_at, .. } => {
+                last_ops.insert(key, Some(Some(expires_at)))
+            }
+        };
+    }
+
+    let mut uncompacted = 0;
+    let mut live = 0;
+    for (key, set) in last_ops {
+        let old_cmd = if let Some(expires_at) = set {
+            live += 1;
+            let old_cmd = index.get(&key).map(|entry| *entry.value());
+            index.insert(key, cmd_pos.expiring_at(expires_at));
+            old_cmd
+        } else {
+            index.remove(&key).map(|entry| *entry.value())
+        };
+        if let Some(old_cmd) = old_cmd {
+            uncompacted += batches.release(old_cmd);
+        }
+    }
+    // a batch of removes can be deleted in the next compaction
+    if live == 0 {
+        uncompacted += cmd_pos.len;
+    }
+    batches.insert(cmd_pos, live);
+    uncompacted
+}
+
+/// Number of keys still pointing at each batch record that sets several keys,
+/// by generation and offset.
+///
+/// A batch record can only be deleted in a compaction once none of its keys
+/// points at it, so its length counts as stale only when the last one goes.
+#[derive(Default)]
+struct BatchRefs(BTreeMap<(u64, u64), usize>);
+
+impl BatchRefs {
+    /// Records that `live` keys point at the batch record at `cmd_pos`.
+    fn insert(&mut self, cmd_pos: CommandPos, live: usize) {
+        // a record with a single key is stale as soon as that key is
+        if live > 1 {
+            self.0.insert((cmd_pos.gen, cmd_pos.pos), live);
+        }
+    }
+
+    /// Returns how many bytes become stale when a key stops pointing at the
+    /// record at `old_pos`.
+    fn release(&mut self, old_pos: CommandPos) -> u64 {
+        let id = (old_pos.gen, old_pos.pos);
+        match self.0.get_mut(&id) {
+            Some(live) if *live > 2 => {
+                *live -= 1;
+                0
+            }
+            // the record is left with a single key
+            Some(_) => {
+                self.0.remove(&id);
+                0
+            }
+            None => old_pos.len,
+        }
+    }
+
+    /// Forgets the records of the generations older than `gen`, which a
+    /// compaction has removed.
+    fn remove_older(&mut self, gen: u64) {
+        self.0 = self.0.split_off(&(gen, 0));
+    }
+}
+
+/// Returns the position of the value of `key` unless it is missing or expired.
+fn live_pos(index: &SkipMap<Vec<u8>, CommandPos>, key: &[u8]) -> Option<CommandPos> {
+    let cmd_pos = *index.get(key)?.value();
+    if cmd_pos.is_expired(now_millis()) {
+        None
+    } else {
+        Some(cmd_pos)
+    }
 }
 
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
@@ -379,28 +964,76 @@ fn log_path(dir: &Path, gen: u64) -> PathBuf {
 }
 
 /// Struct representing a command
+///
+/// Keys and values used to be `String`s. Bincode encodes a `String` exactly
+/// like a `Vec<u8>`, so logs written back then are still read correctly.
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    Batch {
+        ops: Vec<BatchOp>,
+    },
+    // `expires_at` is in milliseconds since the Unix epoch
+    SetWithExpiry {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: u64,
+    },
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn set(key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Command {
+        match expires_at {
+            Some(expires_at) => Command::SetWithExpiry {
+                key,
+                value,
+                expires_at,
+            },
+            None => Command::Set { key, value },
+        }
     }
 
-    fn remove(key: String) -> Command {
+    fn remove(key: Vec<u8>) -> Command {
         Command::Remove { key }
     }
+
+    fn batch(batch: WriteBatch) -> Command {
+        Command::Batch {
+            ops: batch.into_ops(),
+        }
+    }
 }
 
-/// Represents the position and length of a json-serialized command in the log
-#[derive(Debug, Clone, Copy)]
+/// Represents the position and length of a command record in the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
+    // milliseconds since the Unix epoch after which the value is gone
+    expires_at: Option<u64>,
+    // sequence number of the write, only meaningful until the store is closed
+    seq: u64,
+}
+
+impl CommandPos {
+    fn expiring_at(self, expires_at: Option<u64>) -> CommandPos {
+        CommandPos { expires_at, ..self }
+    }
+
+    fn at_seq(self, seq: u64) -> CommandPos {
+        CommandPos { seq, ..self }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 impl From<(u64, Range<u64>)> for CommandPos {
@@ -409,6 +1042,8 @@ impl From<(u64, Range<u64>)> for CommandPos {
             gen,
             pos: range.start,
             len: range.end - range.start,
+            expires_at: None,
+            seq: 0,
         }
     }
 }
@@ -456,6 +1091,10 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
             pos,
         })
     }
+
+    fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
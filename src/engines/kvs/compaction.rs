@@ -0,0 +1,249 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crossbeam_skiplist::SkipMap;
+use log::{error, info, warn};
+
+use super::hint::{hint_path, hint_tmp_path, write_hint};
+use super::record::{write_file_header, write_record, LOG_MAGIC};
+use super::{
+    log_path, sorted_gen_list, BufWriterWithPos, Command, CommandPos, KvStoreReader, KvStoreWriter,
+};
+use crate::engines::expiry::now_millis;
+use crate::Result;
+
+/// Messages sent to the compaction thread.
+pub(super) enum Message {
+    /// Compact every log older than the given generation into that generation,
+    /// which reclaims the given number of stale bytes.
+    Compact(u64, u64),
+    Shutdown,
+}
+
+/// Handle to the background compaction thread.
+///
+/// The thread is stopped and joined when the handle is dropped. A compaction
+/// in progress is abandoned and leaves the logs as they were.
+pub(super) struct Compactor {
+    sender: Sender<Message>,
+    stopping: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    /// Spawns the compaction thread serving requests from `receiver`.
+    ///
+    /// `sender` must be connected to `receiver`. It is used to stop the thread.
+    pub(super) fn spawn(
+        sender: Sender<Message>,
+        receiver: Receiver<Message>,
+        task: CompactionTask,
+    ) -> Compactor {
+        let stopping = Arc::clone(&task.stopping);
+        let handle = thread::spawn(move || {
+            for msg in receiver {
+                match msg {
+                    Message::Compact(compaction_gen, stale) => task.run(compaction_gen, stale),
+                    Message::Shutdown => break,
+                }
+            }
+        });
+        Compactor {
+            sender,
+            stopping,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        let _ = self.sender.send(Message::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Everything the compaction thread needs to rewrite the logs.
+pub(super) struct CompactionTask {
+    pub(super) path: Arc<PathBuf>,
+    pub(super) index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    pub(super) reader: KvStoreReader,
+    pub(super) writer: Arc<Mutex<KvStoreWriter>>,
+    pub(super) stopping: Arc<AtomicBool>,
+}
+
+impl CompactionTask {
+    fn run(&self, compaction_gen: u64, stale: u64) {
+        let result = self.compact(compaction_gen, stale);
+        if let Err(e) = &result {
+            error!("Compaction into generation {} failed: {}", compaction_gen, e);
+        }
+        if !matches!(result, Ok(true)) {
+            let tmp_path = compaction_path(&self.path, compaction_gen);
+            let _ = fs::remove_file(tmp_path);
+            // allow the writer to trigger another compaction
+            self.writer.lock().unwrap().compacting = false;
+        }
+    }
+
+    /// Copies the live entries of the sealed logs into the log with generation
+    /// `compaction_gen`, writes its hint file and removes the sealed logs.
+    ///
+    /// The writer keeps appending to newer generations meanwhile. The writer lock
+    /// is only taken to point the index at the copies, which is done only for
+    /// entries still pointing at the location they were copied from.
+    ///
+    /// `stale` is the number of stale bytes in the sealed logs, which is taken
+    /// off `KvStoreWriter::uncompacted` once they are gone. A compaction that
+    /// fails leaves the count as it was, so that another one is triggered.
+    ///
+    /// Returns `false` if the compaction is abandoned because the store is closing.
+    fn compact(&self, compaction_gen: u64, stale: u64) -> Result<bool> {
+        let tmp_path = compaction_path(&self.path, compaction_gen);
+        let mut compaction_writer = BufWriterWithPos::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&tmp_path)?,
+        )?;
+        write_file_header(&mut compaction_writer, &LOG_MAGIC)?;
+
+        let now = now_millis();
+        let mut copied = Vec::new();
+        let mut expired = Vec::new();
+        for entry in self.index.iter() {
+            if self.stopping.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            let old_pos = *entry.value();
+            // entries in newer generations are written after the compaction started
+            if old_pos.gen >= compaction_gen {
+                continue;
+            }
+            if old_pos.is_expired(now) {
+                expired.push((entry.key().clone(), old_pos));
+                continue;
+            }
+            // Decode and re-encode instead of copying raw bytes so that a damaged
+            // record is detected here rather than carried over to the new file.
+            // It also splits batches into one record per live key.
+            let value = self.reader.read_value(entry.key(), old_pos)?;
+            let cmd = Command::set(entry.key().clone(), value, old_pos.expires_at);
+            let pos = compaction_writer.pos;
+            write_record(&mut compaction_writer, &cmd)?;
+            // the copy keeps the sequence number, it is the same version of the key
+            let new_pos = CommandPos {
+                gen: compaction_gen,
+                pos,
+                len: compaction_writer.pos - pos,
+                ..old_pos
+            };
+            copied.push((entry.key().clone(), old_pos, new_pos));
+        }
+
+        // The stale logs are deleted below, so the compacted copy must be durable
+        // whatever the sync policy is.
+        compaction_writer.flush()?;
+        compaction_writer.get_ref().sync_all()?;
+        let log_len = compaction_writer.pos;
+        drop(compaction_writer);
+        fs::rename(&tmp_path, log_path(&self.path, compaction_gen))?;
+
+        // The hint only speeds up the next open, so failing to write it is not fatal.
+        let hint_entries = copied.iter().map(|(key, _, new_pos)| (key, *new_pos));
+        if let Err(e) = write_hint(&self.path, compaction_gen, log_len, hint_entries) {
+            warn!("Hint file for generation {} cannot be written: {}", compaction_gen, e);
+            let _ = fs::remove_file(hint_tmp_path(&self.path, compaction_gen));
+        }
+        sync_dir(&self.path)?;
+
+        let removable = {
+            let mut writer = self.writer.lock().unwrap();
+            let mut dead = 0;
+            for (key, old_pos, new_pos) in copied {
+                match self.index.get(&key) {
+                    Some(entry) if *entry.value() == old_pos => {
+                        self.index.insert(key, new_pos);
+                    }
+                    // overwritten or removed while being copied
+                    _ => dead += new_pos.len,
+                }
+            }
+            // expired entries are not copied, so they must leave the index with
+            // the logs they point to
+            for (key, old_pos) in expired {
+                if matches!(self.index.get(&key), Some(entry) if *entry.value() == old_pos) {
+                    let seq = writer.next_seq();
+                    writer.preserve(&key, seq);
+                    self.index.remove(&key);
+                }
+            }
+            writer.uncompacted = writer.uncompacted.saturating_sub(stale) + dead;
+            writer.batches.remove_older(compaction_gen);
+            writer.compacting = false;
+
+            self.reader
+                .safe_point
+                .store(compaction_gen, Ordering::SeqCst);
+
+            // logs a snapshot may read are removed once it is dropped
+            let stale_gens = sorted_gen_list(&self.path)?
+                .into_iter()
+                .filter(|&gen| gen < compaction_gen);
+            writer.pins.removable(stale_gens)
+        };
+        self.reader.close_stale_handles();
+        remove_logs(&self.path, removable);
+        info!("Compacted logs into generation {}", compaction_gen);
+
+        Ok(true)
+    }
+}
+
+/// Removes the stale logs of the given generations and their hint files.
+///
+/// Note that actually these files are not deleted immediately because `KvStoreReader`s
+/// still keep open file handles. When `KvStoreReader` is used next time, it will clear
+/// its stale file handles. On Unix, the files will be deleted after all the handles
+/// are closed. On Windows, the deletions below will fail and stale files are expected
+/// to be deleted in the next compaction.
+pub(super) fn remove_logs(dir: &Path, gens: Vec<u64>) {
+    for gen in gens {
+        let file_path = hint_path(dir, gen);
+        if file_path.exists() {
+            if let Err(e) = fs::remove_file(&file_path) {
+                error!("{:?} cannot be deleted: {}", file_path, e);
+            }
+        }
+        let file_path = log_path(dir, gen);
+        if let Err(e) = fs::remove_file(&file_path) {
+            error!("{:?} cannot be deleted: {}", file_path, e);
+        }
+    }
+}
+
+/// Path of the file a compaction writes to before it is complete.
+fn compaction_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.compacting", gen))
+}
+
+/// Makes a rename in the directory durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
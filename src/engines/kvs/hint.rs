@@ -0,0 +1,161 @@
+//! Hint files let `KvStore::open` rebuild the index without reading values.
+//!
+//! A hint file `<gen>.hint` is written next to each log produced by a
+//! compaction. It holds a `HintHeader` record followed by one `HintEntry`
+//! record per record in the log, in the format of the `record` module.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crossbeam_skiplist::SkipMap;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::record::{
+    read_file_header, read_record, write_file_header, write_record, FILE_HEADER_LEN, HINT_MAGIC,
+};
+use super::{log_path, BatchRefs, CommandPos};
+use crate::{KvsError, Result};
+
+#[derive(Serialize, Deserialize)]
+struct HintHeader {
+    /// length of the log file the hint describes
+    log_len: u64,
+    /// number of entries following the header
+    entries: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HintEntry {
+    key: Vec<u8>,
+    gen: u64,
+    pos: u64,
+    len: u64,
+    expires_at: Option<u64>,
+}
+
+/// Writes the hint file for the log with generation `gen`.
+///
+/// The file is written under a temporary name and renamed once complete, so a
+/// hint file that exists is never partially written.
+pub(super) fn write_hint<'a, I>(dir: &Path, gen: u64, log_len: u64, entries: I) -> Result<()>
+where
+    I: ExactSizeIterator<Item = (&'a Vec<u8>, CommandPos)>,
+{
+    let tmp_path = hint_tmp_path(dir, gen);
+    let mut writer = BufWriter::new(
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?,
+    );
+    write_file_header(&mut writer, &HINT_MAGIC)?;
+    let header = HintHeader {
+        log_len,
+        entries: entries.len() as u64,
+    };
+    write_record(&mut writer, &header)?;
+    for (key, cmd_pos) in entries {
+        let entry = HintEntry {
+            key: key.clone(),
+            gen: cmd_pos.gen,
+            pos: cmd_pos.pos,
+            len: cmd_pos.len,
+            expires_at: cmd_pos.expires_at,
+        };
+        write_record(&mut writer, &entry)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+    fs::rename(&tmp_path, hint_path(dir, gen))?;
+    Ok(())
+}
+
+/// Loads the hint file of the log with generation `gen` into the index.
+///
+/// Returns `None` if there is no usable hint file, in which case the log has to
+/// be replayed. Otherwise returns how many bytes can be saved after a compaction.
+pub(super) fn load_hint(
+    dir: &Path,
+    gen: u64,
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    batches: &mut BatchRefs,
+) -> Result<Option<u64>> {
+    let path = hint_path(dir, gen);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let log_len = fs::metadata(log_path(dir, gen))?.len();
+
+    // Entries are collected first so a damaged hint leaves the index untouched.
+    let entries = match read_hint(&path, gen, log_len) {
+        Ok(entries) => entries,
+        Err(e @ KvsError::Corruption { .. }) => {
+            warn!("Ignoring hint file {:?}: {}", path, e);
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut uncompacted = 0;
+    for entry in entries {
+        if let Some(old_cmd) = index.get(&entry.key) {
+            uncompacted += batches.release(*old_cmd.value());
+        }
+        let cmd_pos = CommandPos {
+            gen: entry.gen,
+            pos: entry.pos,
+            len: entry.len,
+            expires_at: entry.expires_at,
+            seq: 0,
+        };
+        index.insert(entry.key, cmd_pos);
+    }
+    Ok(Some(uncompacted))
+}
+
+fn read_hint(path: &Path, gen: u64, log_len: u64) -> Result<Vec<HintEntry>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    read_file_header(&mut reader, gen, &HINT_MAGIC)?;
+    let mut pos = FILE_HEADER_LEN;
+
+    let corruption = |pos, reason: &str| KvsError::Corruption {
+        gen,
+        pos,
+        reason: format!("hint file: {}", reason),
+    };
+    let header: HintHeader = match read_record(&mut reader, gen, pos)? {
+        Some((header, len)) => {
+            pos += len;
+            header
+        }
+        None => return Err(corruption(pos, "missing header")),
+    };
+    if header.log_len != log_len {
+        return Err(corruption(0, "log length mismatch"));
+    }
+
+    let mut entries = Vec::with_capacity(header.entries as usize);
+    while let Some((entry, len)) = read_record::<_, HintEntry>(&mut reader, gen, pos)? {
+        if entry.gen != gen || entry.pos + entry.len > log_len {
+            return Err(corruption(pos, "entry out of range"));
+        }
+        entries.push(entry);
+        pos += len;
+    }
+    if entries.len() as u64 != header.entries {
+        return Err(corruption(pos, "missing entries"));
+    }
+    Ok(entries)
+}
+
+pub(super) fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+pub(super) fn hint_tmp_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint.tmp", gen))
+}
@@ -0,0 +1,98 @@
+use crate::engines::SyncPolicy;
+
+/// Default number of stale bytes in the logs that triggers a compaction.
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// Options to configure how a `KvStore` is opened.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let options = KvStoreOptions::new()
+///     .compaction_threshold(64 * 1024 * 1024)
+///     .max_log_file_size(256 * 1024 * 1024);
+/// let store = KvStore::open_with(current_dir()?, options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(super) compaction_threshold: u64,
+    pub(super) max_log_file_size: Option<u64>,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) read_only: bool,
+    pub(super) create_if_missing: bool,
+    pub(super) recover_sealed_logs: bool,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            max_log_file_size: None,
+            sync_policy: SyncPolicy::Never,
+            read_only: false,
+            create_if_missing: true,
+            recover_sealed_logs: false,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Creates options with the default settings.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Sets how many bytes of stale records are tolerated before the logs are
+    /// compacted. Defaults to 1 MiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> KvStoreOptions {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Sets the size after which the active log file is sealed and writes move
+    /// on to a new one. Log files are not limited by default.
+    pub fn max_log_file_size(mut self, bytes: u64) -> KvStoreOptions {
+        self.max_log_file_size = Some(bytes);
+        self
+    }
+
+    /// Sets when writes are forced to disk. Defaults to `SyncPolicy::Never`.
+    ///
+    /// Every write is handed to the operating system before it is acknowledged
+    /// regardless of the policy. The policy decides when it is fsynced.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> KvStoreOptions {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Sets whether the store is opened read-only. Defaults to `false`.
+    ///
+    /// A read-only store never touches the files on disk: writes fail with
+    /// `KvsError::ReadOnly`, no compaction runs and a torn write at the end of the
+    /// newest log is skipped instead of truncated.
+    pub fn read_only(mut self, read_only: bool) -> KvStoreOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets whether the directory is created if it does not exist. Defaults to `true`.
+    ///
+    /// It has no effect on a read-only store, which never creates the directory.
+    pub fn create_if_missing(mut self, create: bool) -> KvStoreOptions {
+        self.create_if_missing = create;
+        self
+    }
+
+    /// Sets whether a corrupted record is tolerated, other than the torn write at
+    /// the end of the newest log file that is always recovered from.
+    ///
+    /// By default such a record fails the open. When enabled, the replay of the
+    /// damaged file stops at the bad record and the rest of the file is ignored.
+    pub fn recover_sealed_logs(mut self, recover: bool) -> KvStoreOptions {
+        self.recover_sealed_logs = recover;
+        self
+    }
+}
@@ -0,0 +1,164 @@
+//! On-disk format of `KvStore` log and hint files.
+//!
+//! Every file starts with a fixed header:
+//!
+//! ```text
+//! +-------------+--------------------+
+//! | magic (4B)  | format version (4B)|
+//! +-------------+--------------------+
+//! ```
+//!
+//! followed by a sequence of records:
+//!
+//! ```text
+//! +-------------+-------------+----------------------+
+//! | length (4B) | crc32 (4B)  | body (`length` bytes)|
+//! +-------------+-------------+----------------------+
+//! ```
+//!
+//! Integers are little-endian. The body is bincode-serialized and the checksum
+//! covers the body only. The magic tells log files from hint files.
+
+use std::io::{self, Read, Write};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{KvsError, Result};
+
+/// Magic bytes at the beginning of every log file.
+pub(super) const LOG_MAGIC: [u8; 4] = *b"KVSL";
+
+/// Magic bytes at the beginning of every hint file.
+pub(super) const HINT_MAGIC: [u8; 4] = *b"KVSH";
+
+/// Version of the file format written by this build.
+pub(super) const FORMAT_VERSION: u32 = 1;
+
+/// Length of the file header in bytes.
+pub(super) const FILE_HEADER_LEN: u64 = 8;
+
+/// Length of the per-record header (length + checksum) in bytes.
+const RECORD_HEADER_LEN: usize = 8;
+
+// Reasons of the corruptions that are a file cut short in the middle of a write.
+const TRUNCATED_FILE_HEADER: &str = "truncated file header";
+const TRUNCATED_RECORD_HEADER: &str = "truncated record header";
+const TRUNCATED_RECORD_BODY: &str = "truncated record body";
+
+/// Writes the file header with the given magic.
+pub(super) fn write_file_header<W: Write>(writer: &mut W, magic: &[u8; 4]) -> Result<()> {
+    writer.write_all(magic)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+/// Reads and validates the header of the file with generation `gen`.
+pub(super) fn read_file_header<R: Read>(reader: &mut R, gen: u64, magic: &[u8; 4]) -> Result<()> {
+    let mut header = [0; FILE_HEADER_LEN as usize];
+    if read_full(reader, &mut header)? < header.len() {
+        return Err(corruption(gen, 0, TRUNCATED_FILE_HEADER));
+    }
+    if header[..4] != magic[..] {
+        return Err(corruption(gen, 0, "bad magic"));
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if version != FORMAT_VERSION {
+        return Err(corruption(
+            gen,
+            0,
+            format!("unsupported format version {}", version),
+        ));
+    }
+    Ok(())
+}
+
+/// Serializes `value` as a single record.
+///
+/// Returns the number of bytes written including the record header.
+pub(super) fn write_record<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<u64> {
+    let body = bincode::serialize(value)?;
+    let len = u32::try_from(body.len())
+        .map_err(|_| KvsError::StringError("record is too large".to_owned()))?;
+    let crc = crc32fast::hash(&body);
+
+    let mut header = [0; RECORD_HEADER_LEN];
+    header[..4].copy_from_slice(&len.to_le_bytes());
+    header[4..].copy_from_slice(&crc.to_le_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(&body)?;
+    Ok((RECORD_HEADER_LEN + body.len()) as u64)
+}
+
+/// Reads a single record located at `pos` in the log with generation `gen`.
+///
+/// Returns `None` if the reader is exhausted before the record starts. A
+/// partially written record, a checksum mismatch or an undecodable body is
+/// reported as `KvsError::Corruption`.
+pub(super) fn read_record<R: Read, T: DeserializeOwned>(
+    reader: &mut R,
+    gen: u64,
+    pos: u64,
+) -> Result<Option<(T, u64)>> {
+    let mut header = [0; RECORD_HEADER_LEN];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        RECORD_HEADER_LEN => {}
+        _ => return Err(corruption(gen, pos, TRUNCATED_RECORD_HEADER)),
+    }
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+    // `take` makes sure a garbage length does not turn into a huge allocation.
+    let mut body = Vec::new();
+    reader.take(len as u64).read_to_end(&mut body)?;
+    if body.len() < len {
+        return Err(corruption(gen, pos, TRUNCATED_RECORD_BODY));
+    }
+    if crc32fast::hash(&body) != crc {
+        return Err(corruption(gen, pos, "checksum mismatch"));
+    }
+    let value = bincode::deserialize(&body)
+        .map_err(|e| corruption(gen, pos, format!("malformed record body: {}", e)))?;
+    Ok(Some((value, (RECORD_HEADER_LEN + len) as u64)))
+}
+
+/// Tells whether `err` is a file or record header, or a record body, that the
+/// end of the file cuts short, which is what a crash in the middle of a write
+/// leaves behind. Any other damage is not a torn write.
+pub(super) fn is_torn(err: &KvsError) -> bool {
+    match err {
+        KvsError::Corruption { reason, .. } => [
+            TRUNCATED_FILE_HEADER,
+            TRUNCATED_RECORD_HEADER,
+            TRUNCATED_RECORD_BODY,
+        ]
+        .contains(&reason.as_str()),
+        _ => false,
+    }
+}
+
+fn corruption(gen: u64, pos: u64, reason: impl Into<String>) -> KvsError {
+    KvsError::Corruption {
+        gen,
+        pos,
+        reason: reason.into(),
+    }
+}
+
+/// Fills `buf` as much as possible and returns the number of bytes read.
+///
+/// Unlike `read_exact`, hitting the end of the input is not an error so the
+/// caller can tell a clean end from a partial read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
@@ -0,0 +1,119 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crossbeam_skiplist::SkipMap;
+
+use super::snapshot::View;
+use super::{CommandPos, KvStoreReader};
+use crate::engines::expiry::now_millis;
+use crate::Result;
+
+/// A lazy, ordered iterator over a range of the `KvStore` index.
+///
+/// It does not borrow the index: every step looks up the first key after the
+/// last one returned, so concurrent writes never invalidate it. Values are read
+/// from the logs as the iterator advances.
+pub(super) struct KvStoreScan {
+    index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    reader: KvStoreReader,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    // stop at the first key without this prefix
+    prefix: Option<Vec<u8>>,
+    remaining: Option<usize>,
+    // read the index as a snapshot saw it instead
+    view: Option<View>,
+}
+
+impl KvStoreScan {
+    pub(super) fn new(
+        index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+        reader: KvStoreReader,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> KvStoreScan {
+        KvStoreScan {
+            index,
+            reader,
+            start,
+            end,
+            prefix: None,
+            remaining: limit,
+            view: None,
+        }
+    }
+
+    pub(super) fn with_prefix(
+        index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+        reader: KvStoreReader,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> KvStoreScan {
+        let start = Bound::Included(prefix.clone());
+        let mut scan = KvStoreScan::new(index, reader, start, Bound::Unbounded, limit);
+        scan.prefix = Some(prefix);
+        scan
+    }
+
+    /// Makes the scan return the entries as of the snapshot `view`.
+    pub(super) fn in_snapshot(self, view: View) -> KvStoreScan {
+        KvStoreScan {
+            view: Some(view),
+            ..self
+        }
+    }
+
+    fn next_entry(&mut self) -> Option<(Vec<u8>, CommandPos)> {
+        if self.remaining == Some(0) {
+            return None;
+        }
+        loop {
+            let (key, cmd_pos) = match &self.view {
+                Some(view) => (view.next_key(&self.start, &self.end)?, None),
+                None => {
+                    let range = (self.start.clone(), self.end.clone());
+                    let entry = self.index.range(range).next()?;
+                    (entry.key().clone(), Some(*entry.value()))
+                }
+            };
+            if let Some(prefix) = &self.prefix {
+                if !key.starts_with(prefix) {
+                    return None;
+                }
+            }
+            self.start = Bound::Excluded(key.clone());
+            let cmd_pos = match &self.view {
+                Some(view) => view.pos(&key),
+                None => cmd_pos.filter(|cmd_pos| !cmd_pos.is_expired(now_millis())),
+            };
+            let Some(cmd_pos) = cmd_pos else {
+                continue;
+            };
+            if let Some(remaining) = self.remaining.as_mut() {
+                *remaining -= 1;
+            }
+            return Some((key, cmd_pos));
+        }
+    }
+}
+
+impl Iterator for KvStoreScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, cmd_pos) = self.next_entry()?;
+            match self.reader.read_value(&key, cmd_pos) {
+                Err(e) if self.reader.is_moved(&e, cmd_pos) => {
+                    // look the key up again to find where the compaction moved it
+                    self.start = Bound::Included(key);
+                    if let Some(remaining) = self.remaining.as_mut() {
+                        *remaining += 1;
+                    }
+                }
+                result => return Some(result.map(|value| (key, value))),
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Point-in-time snapshots of a `KvStore`.
+//!
+//! Every write is stamped with a sequence number and a snapshot remembers the
+//! last one it can see. While snapshots are alive, the writer saves the index
+//! entries it overwrites or removes to a history, where a snapshot finds the
+//! value a key had when it was taken. Logs a compaction makes stale are kept
+//! on disk until no snapshot can read them.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use crossbeam_skiplist::SkipMap;
+
+use super::compaction::remove_logs;
+use super::scan::KvStoreScan;
+use super::{CommandPos, KvStore, KvStoreReader, KvStoreWriter};
+use crate::engines::expiry::now_millis;
+use crate::engines::{ByteScan, KvsSnapshot};
+use crate::Result;
+
+/// Index entries overwritten or removed while snapshots are alive.
+///
+/// The entry at `(key, seq)` holds the position of the value `key` had right
+/// before the write with sequence number `seq`, or `None` if it was missing.
+pub(super) type History = SkipMap<(Vec<u8>, u64), Option<CommandPos>>;
+
+/// The live snapshots of a store and the stale logs they keep.
+///
+/// It belongs to the writer, so it only changes under the writer lock.
+#[derive(Default)]
+pub(super) struct Pins {
+    next_id: u64,
+    // maps a snapshot to its sequence number and to the first generation it may read
+    live: BTreeMap<u64, (u64, u64)>,
+    // stale logs that are not removed yet because a snapshot may read them
+    deferred: BTreeSet<u64>,
+}
+
+impl Pins {
+    pub(super) fn is_empty(&self) -> bool {
+        self.live.is_empty()
+    }
+
+    /// Registers a snapshot and returns its id.
+    fn add(&mut self, seq: u64, first_gen: u64) -> u64 {
+        self.next_id += 1;
+        self.live.insert(self.next_id, (seq, first_gen));
+        self.next_id
+    }
+
+    /// Unregisters the snapshot `id` and drops the history entries no other
+    /// snapshot needs.
+    ///
+    /// Returns the stale logs that can be removed now.
+    fn remove(&mut self, id: u64, history: &History) -> Vec<u64> {
+        self.live.remove(&id);
+        // an entry is only read by the snapshots taken before its write
+        match self.live.values().map(|&(seq, _)| seq).min() {
+            Some(oldest) => {
+                for entry in history.iter() {
+                    if entry.key().1 <= oldest {
+                        entry.remove();
+                    }
+                }
+            }
+            None => history.clear(),
+        }
+        let deferred = std::mem::take(&mut self.deferred);
+        self.removable(deferred)
+    }
+
+    /// Returns the stale logs among `gens` that can be removed now and keeps
+    /// the others until the snapshots that may read them are dropped.
+    pub(super) fn removable(&mut self, gens: impl IntoIterator<Item = u64>) -> Vec<u64> {
+        let pinned_from = self.live.values().map(|&(_, first_gen)| first_gen).min();
+        let mut removable = Vec::new();
+        for gen in gens {
+            if pinned_from.is_some_and(|first_gen| first_gen <= gen) {
+                self.deferred.insert(gen);
+            } else {
+                self.deferred.remove(&gen);
+                removable.push(gen);
+            }
+        }
+        removable
+    }
+}
+
+/// A read-only view of a `KvStore` as of the moment it was taken.
+///
+/// It is returned by `KvsEngine::snapshot`. Writes made after that are not
+/// visible to it, and neither are keys expiring after that. The logs it reads
+/// from are kept on disk until it is dropped, so a long-lived snapshot holds
+/// on to the space a compaction would otherwise free.
+pub struct KvStoreSnapshot {
+    view: View,
+    reader: KvStoreReader,
+}
+
+impl KvStoreSnapshot {
+    pub(super) fn new(store: &KvStore) -> KvStoreSnapshot {
+        let (seq, first_gen, pin) = match store.writer {
+            Some(ref writer) => {
+                let mut guard = writer.lock().unwrap();
+                // the index points nowhere below the safe point while the lock is held
+                let first_gen = store.reader.safe_point.load(Ordering::SeqCst);
+                let seq = guard.seq;
+                let id = guard.pins.add(seq, first_gen);
+                let pin = Pin {
+                    id,
+                    writer: Arc::clone(writer),
+                };
+                (seq, first_gen, Some(Arc::new(pin)))
+            }
+            // nothing changes in a read-only store
+            None => (0, 0, None),
+        };
+        KvStoreSnapshot {
+            view: View {
+                index: Arc::clone(&store.index),
+                history: Arc::clone(&store.history),
+                seq,
+                now: now_millis(),
+                pin,
+            },
+            reader: store.reader.pinned(first_gen),
+        }
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.view.pos(&key) {
+            Some(cmd_pos) => Ok(Some(self.reader.read_value(&key, cmd_pos)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Result<ByteScan> {
+        let scan = KvStoreScan::new(
+            Arc::clone(&self.view.index),
+            self.reader.clone(),
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+            limit,
+        );
+        Ok(Box::new(scan.in_snapshot(self.view.clone())))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<ByteScan> {
+        let scan = KvStoreScan::with_prefix(
+            Arc::clone(&self.view.index),
+            self.reader.clone(),
+            prefix,
+            limit,
+        );
+        Ok(Box::new(scan.in_snapshot(self.view.clone())))
+    }
+}
+
+/// The index of a store as seen by a snapshot.
+#[derive(Clone)]
+pub(super) struct View {
+    index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    history: Arc<History>,
+    seq: u64,
+    // expiry times are compared with the time the snapshot was taken
+    now: u64,
+    // `None` for a read-only store
+    pin: Option<Arc<Pin>>,
+}
+
+impl View {
+    /// Returns the position of the value `key` had when the snapshot was taken,
+    /// unless it was missing or expired.
+    pub(super) fn pos(&self, key: &[u8]) -> Option<CommandPos> {
+        let cmd_pos = match self.lookup(key) {
+            Some(cmd_pos) => cmd_pos,
+            None => {
+                // Moving an entry to a compacted log leaves no history and removes
+                // the entry before inserting it again, so a miss is confirmed
+                // under the writer lock.
+                let _writer = self.pin.as_ref().map(|pin| pin.writer.lock().unwrap());
+                self.lookup(key).flatten()
+            }
+        };
+        cmd_pos.filter(|cmd_pos| !cmd_pos.is_expired(self.now))
+    }
+
+    /// Returns the first key within the bounds that the index or the history holds.
+    pub(super) fn next_key(&self, start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> Option<Vec<u8>> {
+        let current = self
+            .index
+            .range((start.clone(), end.clone()))
+            .next()
+            .map(|entry| entry.key().clone());
+        // sequence numbers of writes start at 1 and never reach `u64::MAX`
+        let start = match start {
+            Bound::Included(key) => Bound::Included((key.clone(), 0)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), u64::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match end {
+            Bound::Included(key) => Bound::Included((key.clone(), u64::MAX)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), 0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let old = self
+            .history
+            .range((start, end))
+            .next()
+            .map(|entry| entry.key().0.clone());
+        match (current, old) {
+            (Some(current), Some(old)) => Some(current.min(old)),
+            (current, old) => current.or(old),
+        }
+    }
+
+    // Returns `None` if the index misses `key` and there is no history for it.
+    fn lookup(&self, key: &[u8]) -> Option<Option<CommandPos>> {
+        let current = self.index.get(key).map(|entry| *entry.value());
+        if let Some(cmd_pos) = current {
+            if cmd_pos.seq <= self.seq {
+                return Some(Some(cmd_pos));
+            }
+        }
+        // The first write to the key after the snapshot saved the value it had.
+        // The history is written before the index, so it is looked up last.
+        let after = (key.to_vec(), self.seq + 1)..=(key.to_vec(), u64::MAX);
+        match self.history.range(after).next() {
+            Some(entry) => Some(*entry.value()),
+            None => current.map(|_| None),
+        }
+    }
+}
+
+/// Keeps a snapshot registered with the writer until it is dropped.
+struct Pin {
+    id: u64,
+    writer: Arc<Mutex<KvStoreWriter>>,
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let (path, removable) = {
+            let mut guard = self.writer.lock().unwrap();
+            let writer = &mut *guard;
+            let removable = writer.pins.remove(self.id, &writer.history);
+            (Arc::clone(&writer.path), removable)
+        };
+        remove_logs(&path, removable);
+    }
+}
@@ -1,13 +1,225 @@
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
+
 use crate::Result;
 
+/// Key/value pairs produced by a scan, in key order.
+pub type Scan = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
+
+/// Binary key/value pairs produced by a scan, in key order.
+pub type ByteScan = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+
+/// A key/value storage engine.
+///
+/// Keys and values are arbitrary bytes. The methods taking and returning
+/// `String`s are convenience wrappers over the byte-oriented ones, and fail
+/// with `KvsError::Utf8Error` when they come across data that is not UTF-8.
 pub trait KvsEngine: Clone + Send + 'static {
-    fn get(&self, key: String) -> Result<Option<String>>;
-    fn set(&self, key: String, value: String) -> Result<()>;
-    fn remove(&self, key: String) -> Result<()>;
+    /// A read-only view of the engine as of a point in time.
+    type Snapshot: KvsSnapshot;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the key does not exist.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Sets the value of a key, overwriting the previous one.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Removes a given key.
+    ///
+    /// It returns `KvsError::KeyNotFound` if the key does not exist.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Sets the value of a key that expires after `ttl`.
+    ///
+    /// An expired key is invisible to `get` and to scans. Setting the key again
+    /// replaces its TTL, and a plain `set` makes it permanent.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Returns the key/value pairs with keys in `range`, in key order.
+    ///
+    /// At most `limit` pairs are returned if a limit is given.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>)
+        -> Result<ByteScan>;
+
+    /// Returns the key/value pairs with keys starting with `prefix`, in key order.
+    ///
+    /// At most `limit` pairs are returned if a limit is given.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<ByteScan>;
+
+    /// Applies all the operations of `batch` or none of them.
+    ///
+    /// The batch is atomic with respect to crashes: after a restart either every
+    /// operation is visible or none is.
+    fn write(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Sets `key` to `new` if its current value is `expected`, atomically.
+    ///
+    /// `None` stands for a missing key: an `expected` of `None` only matches if
+    /// the key does not exist, and a `new` of `None` removes the key.
+    ///
+    /// Returns whether the swap took place.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Sets `key` to `value` if its current value is `expected`, atomically.
+    ///
+    /// The key then expires after `ttl`, or with a `ttl` of `None` keeps the
+    /// expiry time it has, if any. Returns whether the swap took place.
+    fn compare_and_swap_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<bool>;
+
+    /// Applies `batch` if every key in `expected` still has the given value,
+    /// atomically.
+    ///
+    /// `None` stands for a missing key. Returns whether the batch was applied.
+    fn write_if(&self, expected: Vec<(Vec<u8>, Option<Vec<u8>>)>, batch: WriteBatch)
+        -> Result<bool>;
+
+    /// Returns a read-only view of the engine as of now.
+    ///
+    /// Writes made afterwards are not visible through the snapshot, so it can be
+    /// used to read several keys consistently while writes keep arriving.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// Writes out the buffered writes and syncs them to disk, whatever the
+    /// sync policy.
+    fn flush(&self) -> Result<()>;
+
+    /// Begins a transaction over the engine.
+    ///
+    /// See `Transaction` for how conflicts are handled.
+    fn begin(&self) -> Result<Transaction<Self>> {
+        Transaction::new(self.clone())
+    }
+
+    /// Gets the string value of a given string key.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        utf8_value(self.get_bytes(key.into_bytes())?)
+    }
+
+    /// Sets the value of a string key to a string.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Removes a given string key.
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Sets the value of a string key to a string that expires after `ttl`.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Returns the string key/value pairs with keys in `range`, in key order.
+    fn scan<R: RangeBounds<String>>(&self, range: R, limit: Option<usize>) -> Result<Scan> {
+        Ok(string_pairs(self.scan_bytes(byte_range(range), limit)?))
+    }
+
+    /// Returns the string key/value pairs with keys starting with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: String, limit: Option<usize>) -> Result<Scan> {
+        Ok(string_pairs(
+            self.scan_prefix_bytes(prefix.into_bytes(), limit)?,
+        ))
+    }
+
+    /// Sets string `key` to `new` if its current value is `expected`, atomically.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+}
+
+/// A read-only view of a `KvsEngine` as of the moment it was taken.
+///
+/// Reads through a snapshot are consistent with each other: they all see the
+/// writes made before the snapshot was taken and none made after.
+pub trait KvsSnapshot: Send + 'static {
+    /// Gets the value a key had when the snapshot was taken.
+    ///
+    /// Returns `None` if the key did not exist.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Returns the key/value pairs with keys in `range`, in key order.
+    ///
+    /// At most `limit` pairs are returned if a limit is given.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>)
+        -> Result<ByteScan>;
+
+    /// Returns the key/value pairs with keys starting with `prefix`, in key order.
+    ///
+    /// At most `limit` pairs are returned if a limit is given.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<ByteScan>;
+
+    /// Gets the string value a string key had when the snapshot was taken.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        utf8_value(self.get_bytes(key.into_bytes())?)
+    }
+
+    /// Returns the string key/value pairs with keys in `range`, in key order.
+    fn scan<R: RangeBounds<String>>(&self, range: R, limit: Option<usize>) -> Result<Scan> {
+        Ok(string_pairs(self.scan_bytes(byte_range(range), limit)?))
+    }
+
+    /// Returns the string key/value pairs with keys starting with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: String, limit: Option<usize>) -> Result<Scan> {
+        Ok(string_pairs(
+            self.scan_prefix_bytes(prefix.into_bytes(), limit)?,
+        ))
+    }
+}
+
+fn utf8_value(value: Option<Vec<u8>>) -> Result<Option<String>> {
+    Ok(value.map(String::from_utf8).transpose()?)
+}
+
+fn byte_range<R: RangeBounds<String>>(range: R) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    (
+        range.start_bound().map(|key| key.clone().into_bytes()),
+        range.end_bound().map(|key| key.clone().into_bytes()),
+    )
+}
+
+fn string_pairs(scan: ByteScan) -> Scan {
+    Box::new(scan.map(|pair| {
+        let (key, value) = pair?;
+        Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+    }))
 }
 
+#[cfg(feature = "async")]
+mod async_engine;
+mod batch;
+pub(crate) mod expiry;
 mod kvs;
 mod sled;
+mod sync;
+mod transaction;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+#[cfg(feature = "async")]
+pub use self::async_engine::AsyncEngine;
+pub use self::batch::{BatchOp, WriteBatch};
+pub use self::kvs::{KvStore, KvStoreOptions, KvStoreSnapshot};
+pub use self::sled::{SledKvsEngine, SledSnapshot};
+pub use self::sync::SyncPolicy;
+pub use self::transaction::Transaction;
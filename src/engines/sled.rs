@@ -1,41 +1,550 @@
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Duration;
+
 use sled;
+use sled::transaction::{ConflictableTransactionResult, TransactionError, TransactionalTree};
+use sled::{IVec, Transactional};
 
-use super::KvsEngine;
+use super::expiry::{expiry_time, now_millis};
+use super::sync::Syncer;
+use super::{BatchOp, ByteScan, KvsEngine, KvsSnapshot, SyncPolicy, WriteBatch};
 use crate::{Result, KvsError};
 
 
+/// Name of the tree mapping keys set with a TTL to their expiry time.
+const EXPIRY_TREE: &str = "__kvs_expiry";
+
+
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: sled::Db,
+    // expiry time of the keys in `db` that have one, in milliseconds since the Unix epoch
+    expiry: sled::Tree,
+    // snapshots still alive, held shared by the transactions while there are
+    // none and exclusively otherwise
+    snapshots: Arc<RwLock<Vec<Weak<Overlay>>>>,
+    sync_policy: SyncPolicy,
+    // flushes the database periodically with `SyncPolicy::EveryNms`
+    _syncer: Option<Arc<Syncer>>,
 }
 
 
 impl SledKvsEngine {
-    pub fn new(db: sled::Db) -> Self {
-        SledKvsEngine { db }
+    /// Creates an engine that flushes the database on every write.
+    pub fn new(db: sled::Db) -> Result<Self> {
+        SledKvsEngine::with_sync_policy(db, SyncPolicy::Always)
+    }
+
+    /// Creates an engine with the given sync policy.
+    ///
+    /// With `SyncPolicy::Never` the database is flushed only by sled itself.
+    /// Keys that expired while the database was closed are removed.
+    pub fn with_sync_policy(db: sled::Db, sync_policy: SyncPolicy) -> Result<Self> {
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        let syncer = match sync_policy {
+            SyncPolicy::EveryNms(ms) => {
+                let db = db.clone();
+                Some(Arc::new(Syncer::spawn(ms, move || {
+                    db.flush()?;
+                    Ok(())
+                })))
+            }
+            _ => None,
+        };
+        let engine = SledKvsEngine {
+            db,
+            expiry,
+            snapshots: Arc::new(RwLock::new(Vec::new())),
+            sync_policy,
+            _syncer: syncer,
+        };
+        engine.purge_expired()?;
+        Ok(engine)
+    }
+
+    /// Removes the keys whose TTL has elapsed.
+    ///
+    /// Expired keys are invisible anyway, this only reclaims their space.
+    pub fn purge_expired(&self) -> Result<()> {
+        let now = now_millis();
+        for item in self.expiry.iter() {
+            let (key, expires_at) = item?;
+            if is_expired(Some(expires_at), now) {
+                self.transaction(&[&key], |db, expiry| {
+                    // the key may have been set again in the meantime
+                    if is_expired(expiry.get(&key)?, now) {
+                        db.remove(&key)?;
+                        expiry.remove(&key)?;
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+        self.commit()
+    }
+
+    fn live_scan(&self, iter: sled::Iter, limit: Option<usize>) -> ByteScan {
+        let expiry = self.expiry.clone();
+        let now = now_millis();
+        let pairs = iter.filter_map(move |item| {
+            let live_pair = || -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+                let (key, value) = item?;
+                if is_expired(expiry.get(&key)?, now) {
+                    return Ok(None);
+                }
+                Ok(Some((key.to_vec(), value.to_vec())))
+            };
+            live_pair().transpose()
+        });
+        match limit {
+            Some(limit) => Box::new(pairs.take(limit)),
+            None => Box::new(pairs),
+        }
+    }
+
+    /// Runs `f` as a transaction over the data and the expiry trees, which
+    /// may write to `keys`.
+    ///
+    /// While snapshots are alive, the values the keys have are saved for them
+    /// first, and transactions run one at a time so that the saved values are
+    /// the ones the transaction replaces.
+    fn transaction<F, R>(&self, keys: &[&[u8]], f: F) -> Result<R>
+    where
+        F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<R, KvsError>,
+    {
+        let snapshots = self.snapshots.read().unwrap();
+        if snapshots.iter().all(|overlay| overlay.strong_count() == 0) {
+            return transaction(&self.db, &self.expiry, f);
+        }
+        drop(snapshots);
+
+        let mut snapshots = self.snapshots.write().unwrap();
+        snapshots.retain(|overlay| overlay.strong_count() > 0);
+        let overlays: Vec<_> = snapshots.iter().filter_map(Weak::upgrade).collect();
+        for &key in keys {
+            let value = self.db.get(key)?;
+            let expires_at = self.expiry.get(key)?;
+            for overlay in &overlays {
+                overlay.preserve(key, &value, &expires_at);
+            }
+        }
+        transaction(&self.db, &self.expiry, f)
+    }
+
+    fn commit(&self) -> Result<()> {
+        if self.sync_policy == SyncPolicy::Always {
+            self.db.flush()?;
+        }
+        Ok(())
     }
 }
 
 
 impl KvsEngine for SledKvsEngine {
-    fn get(&self, key: String) -> Result<Option<String>> {
+    type Snapshot = SledSnapshot;
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if is_expired(self.expiry.get(&key)?, now_millis()) {
+            return Ok(None);
+        }
         let val = self.db.get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?;
+            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec());
 
         Ok(val)
     }
 
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let _ = self.db.insert(key, value.as_bytes())?;
-        self.db.flush()?;
-        Ok(())
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.transaction(&[&key], |db, expiry| {
+            db.insert(key.as_slice(), value.as_slice())?;
+            expiry.remove(key.as_slice())?;
+            Ok(())
+        })?;
+        self.commit()
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry_time(ttl).to_be_bytes();
+        self.transaction(&[&key], |db, expiry| {
+            db.insert(key.as_slice(), value.as_slice())?;
+            expiry.insert(key.as_slice(), &expires_at)?;
+            Ok(())
+        })?;
+        self.commit()
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let now = now_millis();
+        // an expired key is removed as well, but reported as missing
+        let removed = self.transaction(&[&key], |db, expiry| {
+            let expires_at = expiry.remove(key.as_slice())?;
+            let old = db.remove(key.as_slice())?;
+            Ok(old.is_some() && !is_expired(expires_at, now))
+        })?;
+        self.commit()?;
+        if removed {
+            Ok(())
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>)
+        -> Result<ByteScan>
+    {
+        let range: (Bound<Vec<u8>>, Bound<Vec<u8>>) =
+            (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(self.live_scan(self.db.range(range), limit))
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        let _ = self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<ByteScan> {
+        Ok(self.live_scan(self.db.scan_prefix(prefix), limit))
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        let keys: Vec<_> = batch.ops().iter().map(BatchOp::key).collect();
+        self.transaction(&keys, |db, expiry| apply(db, expiry, &batch))?;
+        self.commit()
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let now = now_millis();
+        let swapped = self.transaction(&[&key], |db, expiry| {
+            let current = live_value(db, expiry, &key, now)?;
+            if current.as_deref() != expected.as_deref() {
+                return Ok(false);
+            }
+            match &new {
+                Some(value) => db.insert(key.as_slice(), value.as_slice())?,
+                None => db.remove(key.as_slice())?,
+            };
+            expiry.remove(key.as_slice())?;
+            Ok(true)
+        })?;
+        if swapped {
+            self.commit()?;
+        }
+        Ok(swapped)
+    }
+
+    fn compare_and_swap_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<bool> {
+        let now = now_millis();
+        let expires_at = ttl.map(|ttl| expiry_time(ttl).to_be_bytes());
+        let swapped = self.transaction(&[&key], |db, expiry| {
+            let current = live_value(db, expiry, &key, now)?;
+            if current.as_deref() != expected.as_deref() {
+                return Ok(false);
+            }
+            db.insert(key.as_slice(), value.as_slice())?;
+            match &expires_at {
+                Some(expires_at) => {
+                    expiry.insert(key.as_slice(), expires_at)?;
+                }
+                // the expiry time of a missing key is left over from an expired value
+                None if current.is_none() => {
+                    expiry.remove(key.as_slice())?;
+                }
+                None => {}
+            }
+            Ok(true)
+        })?;
+        if swapped {
+            self.commit()?;
+        }
+        Ok(swapped)
+    }
+
+    fn write_if(&self, expected: Vec<(Vec<u8>, Option<Vec<u8>>)>, batch: WriteBatch)
+        -> Result<bool>
+    {
+        let now = now_millis();
+        let keys: Vec<_> = batch.ops().iter().map(BatchOp::key).collect();
+        let written = self.transaction(&keys, |db, expiry| {
+            for (key, value) in &expected {
+                let current = live_value(db, expiry, key, now)?;
+                if current.as_deref() != value.as_deref() {
+                    return Ok(false);
+                }
+            }
+            apply(db, expiry, &batch)?;
+            Ok(true)
+        })?;
+        if written {
+            self.commit()?;
+        }
+        Ok(written)
+    }
+
+    /// Returns a view of the live data as of now.
+    ///
+    /// Sled has no snapshots of its own, so the snapshot reads the database
+    /// and the writes made since it was taken save the values they replace
+    /// for it. Taking it only waits for the transactions in progress.
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let mut snapshots = self.snapshots.write().unwrap();
+        snapshots.retain(|overlay| overlay.strong_count() > 0);
+        let overlay = Arc::new(Overlay {
+            now: now_millis(),
+            saved: Mutex::new(BTreeMap::new()),
+        });
+        snapshots.push(Arc::downgrade(&overlay));
+        Ok(SledSnapshot {
+            db: self.db.clone(),
+            expiry: self.expiry.clone(),
+            overlay,
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
         self.db.flush()?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+
+/// A read-only view of a `SledKvsEngine` as of the moment it was taken.
+///
+/// It is returned by `KvsEngine::snapshot`. Reads go to the database, except
+/// for the keys written since, whose values are kept in memory until the
+/// snapshot is dropped.
+#[derive(Clone)]
+pub struct SledSnapshot {
+    db: sled::Db,
+    expiry: sled::Tree,
+    overlay: Arc<Overlay>,
+}
+
+
+impl SledSnapshot {
+    fn pairs(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>), limit: Option<usize>) -> ByteScan {
+        let pairs = SnapshotScan {
+            iter: self.db.range(range.clone()),
+            expiry: self.expiry.clone(),
+            overlay: Arc::clone(&self.overlay),
+            peeked: None,
+            after: range.0,
+            end: range.1,
+        };
+        match limit {
+            Some(limit) => Box::new(pairs.take(limit)),
+            None => Box::new(pairs),
+        }
+    }
+}
+
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let value = self.db.get(&key)?;
+        let expires_at = self.expiry.get(&key)?;
+        // checked after the database, so that a write it misses came after the reads
+        if let Some(saved) = self.overlay.saved.lock().unwrap().get(&key) {
+            return Ok(saved.clone());
+        }
+        if is_expired(expires_at, self.overlay.now) {
+            return Ok(None);
+        }
+        Ok(value.map(|value| value.to_vec()))
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>)
+        -> Result<ByteScan>
+    {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(self.pairs(range, limit))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<ByteScan> {
+        let end = prefix_end(&prefix);
+        Ok(self.pairs((Bound::Included(prefix), end), limit))
+    }
+}
+
+
+/// Values of the keys written since a snapshot was taken, as of that moment.
+struct Overlay {
+    // time the snapshot was taken at, which tells the keys that had expired
+    now: u64,
+    // live value of each key before its first write, `None` for a missing key
+    saved: Mutex<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+
+impl Overlay {
+    /// Saves the value of `key` read from the trees before a write to it,
+    /// unless a previous write saved it already.
+    fn preserve(&self, key: &[u8], value: &Option<IVec>, expires_at: &Option<IVec>) {
+        let mut saved = self.saved.lock().unwrap();
+        if !saved.contains_key(key) {
+            let live = match value {
+                Some(value) if !is_expired(expires_at.clone(), self.now) => Some(value.to_vec()),
+                _ => None,
+            };
+            saved.insert(key.to_vec(), live);
+        }
+    }
+}
+
+
+/// Merges the database with the values saved for a snapshot, in key order.
+struct SnapshotScan {
+    iter: sled::Iter,
+    expiry: sled::Tree,
+    overlay: Arc<Overlay>,
+    // next pair of the database and whether it had expired for the snapshot
+    peeked: Option<(IVec, IVec, bool)>,
+    // bound past the last key returned
+    after: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+
+impl SnapshotScan {
+    fn next_pair(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            if self.peeked.is_none() {
+                if let Some(item) = self.iter.next() {
+                    let (key, value) = item?;
+                    let expired = is_expired(self.expiry.get(&key)?, self.overlay.now);
+                    self.peeked = Some((key, value, expired));
+                }
+            }
+            // Looked up after the database is read, so that a write the
+            // overlay misses came after the read too. Values saved meanwhile
+            // for keys already passed are not needed any more.
+            let saved = self
+                .overlay
+                .saved
+                .lock()
+                .unwrap()
+                .range((self.after.clone(), self.end.clone()))
+                .next()
+                .map(|(key, value)| (key.clone(), value.clone()));
+            let peeked_key = self.peeked.as_ref().map(|(key, ..)| key.to_vec());
+
+            match saved {
+                Some((key, value)) if peeked_key.as_ref().is_none_or(|peeked| key <= *peeked) => {
+                    if peeked_key.as_ref() == Some(&key) {
+                        self.peeked = None;
+                    }
+                    self.after = Bound::Excluded(key.clone());
+                    if let Some(value) = value {
+                        return Ok(Some((key, value)));
+                    }
+                }
+                _ => match self.peeked.take() {
+                    Some((key, value, expired)) => {
+                        self.after = Bound::Excluded(key.to_vec());
+                        if !expired {
+                            return Ok(Some((key.to_vec(), value.to_vec())));
+                        }
+                    }
+                    None => return Ok(None),
+                },
+            }
+        }
+    }
+}
+
+
+impl Iterator for SnapshotScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_pair().transpose()
+    }
+}
+
+
+/// Runs `f` as a transaction over the data and the expiry trees.
+fn transaction<F, R>(db: &sled::Db, expiry: &sled::Tree, f: F) -> Result<R>
+where
+    F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<R, KvsError>,
+{
+    (&**db, expiry)
+        .transaction(|(db, expiry)| f(db, expiry))
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+}
+
+
+/// Returns the bound past every key starting with `prefix`.
+fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+
+/// Applies the operations of `batch` in a transaction, replacing the TTLs of the keys.
+fn apply(
+    db: &TransactionalTree,
+    expiry: &TransactionalTree,
+    batch: &WriteBatch,
+) -> ConflictableTransactionResult<(), KvsError> {
+    for op in batch.ops() {
+        match op {
+            BatchOp::Set { key, value } => {
+                db.insert(key.as_slice(), value.as_slice())?;
+                expiry.remove(key.as_slice())?;
+            }
+            BatchOp::Remove { key } => {
+                db.remove(key.as_slice())?;
+                expiry.remove(key.as_slice())?;
+            }
+            BatchOp::SetWithExpiry { key, value, expires_at } => {
+                db.insert(key.as_slice(), value.as_slice())?;
+                expiry.insert(key.as_slice(), &expires_at.to_be_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+
+/// Reads `key` in a transaction, treating it as missing if it expired at `now`.
+fn live_value(
+    db: &TransactionalTree,
+    expiry: &TransactionalTree,
+    key: &[u8],
+    now: u64,
+) -> ConflictableTransactionResult<Option<IVec>, KvsError> {
+    match db.get(key)? {
+        Some(_) if is_expired(expiry.get(key)?, now) => Ok(None),
+        current => Ok(current),
+    }
+}
+
+
+/// Tells whether an expiry time read from the expiry tree has elapsed at `now`.
+fn is_expired(expires_at: Option<IVec>, now: u64) -> bool {
+    match expires_at {
+        Some(expires_at) => {
+            let mut bytes = [0; 8];
+            if expires_at.len() == bytes.len() {
+                bytes.copy_from_slice(&expires_at);
+            }
+            u64::from_be_bytes(bytes) <= now
+        }
+        None => false,
+    }
+}
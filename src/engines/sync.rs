@@ -0,0 +1,97 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::error;
+
+use crate::{KvsError, Result};
+
+/// Controls when acknowledged writes are forced to durable storage.
+///
+/// It is parsed from `always`, `never` or an interval such as `100ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Fsync before every write is acknowledged.
+    Always,
+    /// Fsync in the background every given number of milliseconds.
+    ///
+    /// A crash may lose the writes acknowledged within the last interval.
+    EveryNms(u64),
+    /// Never fsync explicitly and leave it to the operating system.
+    Never,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            _ => s
+                .strip_suffix("ms")
+                .and_then(|ms| ms.parse().ok())
+                .filter(|&ms| ms > 0)
+                .map(SyncPolicy::EveryNms)
+                .ok_or_else(|| KvsError::StringError(format!("unknown sync policy: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::EveryNms(ms) => write!(f, "{}ms", ms),
+            SyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// A background thread that periodically syncs an engine.
+///
+/// The thread is stopped and joined when the `Syncer` is dropped, after one
+/// last sync.
+pub(crate) struct Syncer {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    /// Spawns a thread calling `sync` every `interval_ms` milliseconds.
+    pub(crate) fn spawn<F>(interval_ms: u64, mut sync: F) -> Syncer
+    where
+        F: FnMut() -> Result<()> + Send + 'static,
+    {
+        let (stop, stopped) = channel::<()>();
+        let interval = Duration::from_millis(interval_ms);
+        let handle = thread::spawn(move || loop {
+            let stopping = match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => false,
+                // the sender is dropped, which means the engine is gone
+                _ => true,
+            };
+            if let Err(e) = sync() {
+                error!("Background sync failed: {}", e);
+            }
+            if stopping {
+                break;
+            }
+        });
+        Syncer {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
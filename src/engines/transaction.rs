@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+
+use super::{utf8_value, KvsEngine, KvsSnapshot, WriteBatch};
+use crate::{KvsError, Result};
+
+/// A read-modify-write transaction over several keys of a `KvsEngine`.
+///
+/// Reads see the engine as of `KvsEngine::begin` along with the transaction's
+/// own writes, which are buffered until `commit`. Conflicts are detected at
+/// commit time: if a key the transaction read has changed since it began, the
+/// commit fails with `KvsError::Conflict` and nothing is written.
+///
+/// Dropping a transaction without committing it aborts it.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let store = KvStore::open(current_dir()?)?;
+/// let mut txn = store.begin()?;
+/// let balance = |value: Option<String>| value.map_or(0, |value| value.parse().unwrap());
+/// let from: u64 = balance(txn.get("from".to_owned())?);
+/// let to: u64 = balance(txn.get("to".to_owned())?);
+/// if from >= 10 {
+///     txn.set("from".to_owned(), (from - 10).to_string())?;
+///     txn.set("to".to_owned(), (to + 10).to_string())?;
+/// }
+/// txn.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    snapshot: E::Snapshot,
+    // values of the keys read, as of the beginning of the transaction
+    reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // last write to each key, `None` for a removal
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(super) fn new(engine: E) -> Result<Transaction<E>> {
+        let snapshot = engine.snapshot()?;
+        Ok(Transaction {
+            engine,
+            snapshot,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        })
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the key does not exist.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.reads.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = self.snapshot.get_bytes(key.clone())?;
+        self.reads.insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// Sets the value of a key.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    /// Removes a given key.
+    ///
+    /// It returns `KvsError::KeyNotFound` if the key does not exist. The key
+    /// counts as read, so the commit fails if it is set in the meantime.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get_bytes(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Gets the string value of a given string key.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        utf8_value(self.get_bytes(key.into_bytes())?)
+    }
+
+    /// Sets the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Removes a given string key.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Applies the writes of the transaction atomically.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Conflict` if a key the transaction read has been
+    /// changed since it began, in which case nothing is written.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        let expected = self.reads.into_iter().collect();
+        if self.engine.write_if(expected, batch)? {
+            Ok(())
+        } else {
+            Err(KvsError::Conflict)
+        }
+    }
+}
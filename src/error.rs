@@ -1,4 +1,5 @@
 use failure::Fail;
+use serde::{Deserialize, Serialize};
 use std::{io, string::FromUtf8Error};
 
 
@@ -25,14 +26,134 @@ pub enum KvsError {
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
 
+    /// Binary serialization or deserialization error.
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
+
+    /// A log record failed validation.
+    /// It indicates a damaged or partially written log file.
+    #[fail(display = "Corrupted log {} at offset {}: {}", gen, pos, reason)]
+    Corruption {
+        /// Generation of the damaged log file.
+        gen: u64,
+        /// Offset of the damaged record in the log file.
+        pos: u64,
+        /// What is wrong with the record.
+        reason: String,
+    },
+
+    /// Writing to a store opened read-only.
+    #[fail(display = "Store is opened read-only")]
+    ReadOnly,
+
     /// Removing non-existing key error.
     #[fail(display = "Key not found")]
     KeyNotFound,
 
+    /// The client and the server speak different versions of the protocol.
+    #[fail(
+        display = "Protocol version mismatch: client speaks {}, server speaks {}",
+        client, server
+    )]
+    ProtocolMismatch {
+        /// Protocol version of the client.
+        client: u32,
+        /// Protocol version of the server.
+        server: u32,
+    },
+
+    /// Committing a transaction that read a key changed since it began.
+    #[fail(display = "Transaction conflict")]
+    Conflict,
+
     /// Unexpected command type error.
     /// It indicates a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+
+    /// A request the server does not accept, such as one not allowed in a
+    /// transaction.
+    #[fail(display = "{}", _0)]
+    InvalidRequest(String),
+
+    /// The server cannot serve the request at the moment.
+    #[fail(display = "{}", _0)]
+    Unavailable(String),
+
+    /// An error returned by the server that no other variant stands for.
+    #[fail(display = "{}", message)]
+    Remote {
+        /// The code the server sent.
+        code: ErrorCode,
+        /// The message the server sent.
+        message: String,
+    },
+}
+
+/// The kind of an error, sent to clients along with its message.
+///
+/// The codes are stable, so clients can act on an error without parsing its
+/// message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The key does not exist.
+    KeyNotFound,
+    /// The data of the store is damaged.
+    ///
+    /// Clients get it as `KvsError::Remote`, since `KvsError::Corruption`
+    /// points into log files they cannot see.
+    Corruption,
+    /// Reading or writing the store failed.
+    Io,
+    /// The store is opened read-only.
+    ReadOnly,
+    /// The transaction was not committed because of a conflicting write.
+    Conflict,
+    /// The server cannot serve the request at the moment.
+    Unavailable,
+    /// The request is not valid.
+    InvalidRequest,
+    /// Any other error.
+    Internal,
+}
+
+impl KvsError {
+    /// Returns the code sent to clients for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::Corruption { .. } | KvsError::UnexpectedCommandType => ErrorCode::Corruption,
+            KvsError::Io(_) | KvsError::Sled(_) => ErrorCode::Io,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::Conflict => ErrorCode::Conflict,
+            KvsError::Unavailable(_) => ErrorCode::Unavailable,
+            KvsError::InvalidRequest(_)
+            | KvsError::Utf8Error(_)
+            | KvsError::ProtocolMismatch { .. } => ErrorCode::InvalidRequest,
+            KvsError::StringError(_) | KvsError::Serde(_) | KvsError::Bincode(_) => {
+                ErrorCode::Internal
+            }
+            KvsError::Remote { code, .. } => *code,
+        }
+    }
+
+    /// Rebuilds an error sent by the server from its code and message.
+    ///
+    /// `Corruption` and `Internal` are kept as `KvsError::Remote` on purpose:
+    /// the damaged log a `KvsError::Corruption` names is on the server, and its
+    /// message already says where. The code survives, so `code()` still tells
+    /// corruption apart.
+    pub(crate) fn from_remote(code: ErrorCode, message: String) -> KvsError {
+        match code {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::ReadOnly => KvsError::ReadOnly,
+            ErrorCode::Conflict => KvsError::Conflict,
+            ErrorCode::Io => KvsError::Io(io::Error::other(message)),
+            ErrorCode::Unavailable => KvsError::Unavailable(message),
+            ErrorCode::InvalidRequest => KvsError::InvalidRequest(message),
+            ErrorCode::Corruption | ErrorCode::Internal => KvsError::Remote { code, message },
+        }
+    }
 }
 
 impl From<io::Error> for KvsError {
@@ -53,6 +174,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> KvsError {
         KvsError::Utf8Error(err.to_string())
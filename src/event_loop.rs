@@ -0,0 +1,487 @@
+//! Event loops serving the kvs protocol over non-blocking sockets.
+//!
+//! An event loop runs on a thread of its own and waits on the sockets of many
+//! connections at once with `mio`. Requests are parsed as their bytes arrive,
+//! and only the engine calls run on the thread pool, a batch of the requests
+//! of a connection at a time. An idle connection then costs its buffers rather
+//! than a thread.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::Scope;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use crate::codec::{Codec, MAX_FRAME_LEN};
+use crate::common::{
+    Handshake, HandshakeScanner, Request, Response, Tagged, MAX_HANDSHAKE_LEN,
+};
+use crate::engines::{KvsEngine, Transaction};
+use crate::metrics::{ConnectionGuard, Metrics};
+use crate::server::{self, Protocol, Timeouts};
+use crate::shutdown::Tracked;
+use crate::thread_pool::ThreadPool;
+use crate::Result;
+
+const WAKER: Token = Token(0);
+// how often the timeouts are checked
+const TICK: Duration = Duration::from_millis(100);
+const READ_CHUNK: usize = 64 * 1024;
+// a connection is not read from while this much of its responses is unsent
+const MAX_PENDING_OUTPUT: usize = 1024 * 1024;
+// nor while this many of its requests wait for the pool
+const MAX_QUEUED_REQUESTS: usize = 1024;
+
+/// Hands connections over to an event loop, and stops it when dropped.
+pub(crate) struct EventLoopHandle {
+    sender: Sender<Message>,
+    waker: Arc<Waker>,
+}
+
+enum Message {
+    Connection {
+        stream: net::TcpStream,
+        connection: ConnectionGuard,
+        tracked: Tracked,
+    },
+    Stop,
+}
+
+impl EventLoopHandle {
+    /// Starts an event loop on a thread of `scope`, running the engine calls on
+    /// `pool`.
+    pub(crate) fn spawn<'scope, 'env, E, T>(
+        scope: &'scope Scope<'scope, 'env>,
+        engine: E,
+        pool: &'env T,
+        metrics: Arc<Metrics>,
+        timeouts: Timeouts,
+    ) -> Result<EventLoopHandle>
+    where
+        E: KvsEngine,
+        T: ThreadPool + Sync,
+    {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (sender, receiver) = mpsc::channel();
+        let (done_sender, done_receiver) = mpsc::channel();
+        let mut event_loop = EventLoop {
+            poll,
+            waker: Arc::clone(&waker),
+            receiver,
+            done_sender,
+            done_receiver,
+            pool,
+            engine,
+            metrics,
+            timeouts,
+            conns: HashMap::new(),
+            next_token: WAKER.0 + 1,
+            last_check: Instant::now(),
+        };
+        scope.spawn(move || {
+            if let Err(e) = event_loop.run() {
+                error!("Event loop failed: {}", e);
+            }
+        });
+        Ok(EventLoopHandle { sender, waker })
+    }
+
+    /// Adds a connection of the kvs protocol to the event loop.
+    pub(crate) fn add(
+        &self,
+        stream: net::TcpStream,
+        connection: ConnectionGuard,
+        tracked: Tracked,
+    ) {
+        let msg = Message::Connection {
+            stream,
+            connection,
+            tracked,
+        };
+        // the connection is dropped if the event loop has failed
+        if self.sender.send(msg).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+}
+
+impl Drop for EventLoopHandle {
+    /// Stops the event loop, closing the connections left.
+    fn drop(&mut self) {
+        let _ = self.sender.send(Message::Stop);
+        let _ = self.waker.wake();
+    }
+}
+
+/// The responses to a batch of requests run on the thread pool.
+struct Done<E: KvsEngine> {
+    token: Token,
+    output: Result<Vec<u8>>,
+    txn: Option<Transaction<E>>,
+}
+
+struct EventLoop<'a, E: KvsEngine, T> {
+    poll: Poll,
+    waker: Arc<Waker>,
+    receiver: Receiver<Message>,
+    done_sender: Sender<Done<E>>,
+    done_receiver: Receiver<Done<E>>,
+    pool: &'a T,
+    engine: E,
+    metrics: Arc<Metrics>,
+    timeouts: Timeouts,
+    conns: HashMap<Token, Conn<E>>,
+    next_token: usize,
+    last_check: Instant,
+}
+
+impl<E: KvsEngine, T: ThreadPool> EventLoop<'_, E, T> {
+    fn run(&mut self) -> Result<()> {
+        let mut events = Events::with_capacity(1024);
+        // without timeouts, the event loop only wakes up for events
+        let Timeouts { idle, request } = self.timeouts;
+        let tick = (idle.is_some() || request.is_some()).then_some(TICK);
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, tick) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e.into());
+            }
+            for event in events.iter() {
+                if event.token() != WAKER {
+                    self.pump(event.token());
+                }
+            }
+            loop {
+                match self.receiver.try_recv() {
+                    Ok(Message::Connection {
+                        stream,
+                        connection,
+                        tracked,
+                    }) => self.add(stream, connection, tracked),
+                    Err(TryRecvError::Empty) => break,
+                    Ok(Message::Stop) | Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+            while let Ok(done) = self.done_receiver.try_recv() {
+                self.finish(done);
+            }
+            if tick.is_some_and(|tick| self.last_check.elapsed() >= tick) {
+                self.check_timeouts();
+            }
+        }
+    }
+
+    fn add(&mut self, stream: net::TcpStream, connection: ConnectionGuard, tracked: Tracked) {
+        let token = Token(self.next_token);
+        let registered = stream.set_nonblocking(true).and_then(|_| {
+            let peer_addr = stream.peer_addr()?;
+            let mut stream = TcpStream::from_std(stream);
+            self.poll
+                .registry()
+                .register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)?;
+            Ok((stream, peer_addr))
+        });
+        let (stream, peer_addr) = match registered {
+            Ok(registered) => registered,
+            Err(e) => {
+                error!("Connection failed: {}", e);
+                return;
+            }
+        };
+        info!("Accepted connection from {}", peer_addr);
+        self.next_token += 1;
+        self.conns.insert(
+            token,
+            Conn {
+                stream,
+                peer_addr,
+                codec: None,
+                handshake: HandshakeScanner::default(),
+                input: Vec::new(),
+                output: Vec::new(),
+                requests: VecDeque::new(),
+                txn: None,
+                busy: false,
+                read_closed: false,
+                closing: false,
+                last_active: Instant::now(),
+                request_since: None,
+                _connection: connection,
+                _tracked: tracked,
+            },
+        );
+        // the client may have sent its handshake already
+        self.pump(token);
+    }
+
+    /// Does what a connection is ready for, and closes it once it is done.
+    fn pump(&mut self, token: Token) {
+        let Some(conn) = self.conns.get_mut(&token) else {
+            return;
+        };
+        // writing first makes room for the responses to what is read
+        let result = conn.write()
+            .and_then(|_| conn.read_and_parse())
+            .and_then(|_| conn.write());
+        if let Err(e) = result {
+            error!("Error on serving client: {}", e);
+            self.close(token);
+            return;
+        }
+        if conn.is_done() {
+            self.close(token);
+        } else {
+            self.dispatch(token);
+        }
+    }
+
+    /// Runs the requests of a connection on the pool, unless some already run.
+    ///
+    /// The requests of a connection run one batch at a time, so that they are
+    /// answered in order and see the writes of the ones before.
+    fn dispatch(&mut self, token: Token) {
+        let Some(conn) = self.conns.get_mut(&token) else {
+            return;
+        };
+        let Some(codec) = conn.codec else {
+            return;
+        };
+        if conn.busy || conn.requests.is_empty() {
+            return;
+        }
+        conn.busy = true;
+        let requests: Vec<_> = conn.requests.drain(..).collect();
+        let mut txn = conn.txn.take();
+        let peer_addr = conn.peer_addr;
+        let engine = self.engine.clone();
+        let metrics = Arc::clone(&self.metrics);
+        let sender = self.done_sender.clone();
+        let waker = Arc::clone(&self.waker);
+        self.pool.spawn(move || {
+            let mut output = Vec::new();
+            let result = requests.into_iter().try_for_each(|Tagged { id, msg: req }| {
+                debug!("Receive request from {}: {:?}", peer_addr, req);
+                let resp = server::handle(&engine, &mut txn, req);
+                metrics.request(Protocol::Kvs, !matches!(resp, Response::Err { .. }));
+                codec.write(&mut output, &Tagged { id, msg: resp })
+            });
+            let done = Done {
+                token,
+                output: result.map(|_| output),
+                txn,
+            };
+            // the event loop is gone if the server has stopped
+            if sender.send(done).is_ok() {
+                let _ = waker.wake();
+            }
+        });
+    }
+
+    fn finish(&mut self, done: Done<E>) {
+        // the transaction is aborted if the connection has been closed meanwhile
+        let Some(conn) = self.conns.get_mut(&done.token) else {
+            return;
+        };
+        conn.busy = false;
+        conn.txn = done.txn;
+        conn.last_active = Instant::now();
+        match done.output {
+            Ok(output) => conn.output.extend_from_slice(&output),
+            Err(e) => {
+                error!("Error on serving client: {}", e);
+                self.close(done.token);
+                return;
+            }
+        }
+        self.pump(done.token);
+    }
+
+    fn check_timeouts(&mut self) {
+        let now = Instant::now();
+        self.last_check = now;
+        let Timeouts { idle, request } = self.timeouts;
+        let expired: Vec<Token> = self
+            .conns
+            .iter()
+            .filter(|(_, conn)| !conn.busy)
+            .filter_map(|(&token, conn)| {
+                let elapsed = now.duration_since(conn.last_active);
+                // a request is timed from its first byte, however quick the ones after are
+                let receiving = conn.request_since
+                    .map_or(Duration::ZERO, |since| now.duration_since(since));
+                let pending = !conn.input.is_empty() || !conn.output.is_empty();
+                if pending && request.is_some_and(|timeout| elapsed.max(receiving) > timeout) {
+                    warn!("Closing connection from {}: request timed out", conn.peer_addr);
+                    Some(token)
+                } else if !pending && idle.is_some_and(|timeout| elapsed > timeout) {
+                    info!("Closing connection from {}: idle for too long", conn.peer_addr);
+                    Some(token)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for token in expired {
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut conn) = self.conns.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut conn.stream);
+        }
+    }
+}
+
+/// A connection of an event loop.
+struct Conn<E: KvsEngine> {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    // set once the handshake is answered
+    codec: Option<Codec>,
+    handshake: HandshakeScanner,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    requests: VecDeque<Tagged<Request>>,
+    // the transaction begun on this connection, away while a batch runs
+    txn: Option<Transaction<E>>,
+    // whether a batch of requests runs on the pool
+    busy: bool,
+    // whether the client has stopped sending
+    read_closed: bool,
+    // whether the connection is closed once the output is sent
+    closing: bool,
+    last_active: Instant,
+    // when the request read in part started to arrive
+    request_since: Option<Instant>,
+    _connection: ConnectionGuard,
+    _tracked: Tracked,
+}
+
+impl<E: KvsEngine> Conn<E> {
+    /// Reads what the socket holds, unless the connection has enough to do.
+    fn read(&mut self) -> Result<()> {
+        while !self.read_closed
+            && self.output.len() < MAX_PENDING_OUTPUT
+            && self.requests.len() < MAX_QUEUED_REQUESTS
+            // room for the largest frame, or the longest handshake
+            && self.input.len() < self.max_input()
+        {
+            let len = self.input.len();
+            self.input.resize(len + READ_CHUNK, 0);
+            let result = self.stream.read(&mut self.input[len..]);
+            self.input.truncate(len + *result.as_ref().unwrap_or(&0));
+            match result {
+                Ok(0) => self.read_closed = true,
+                Ok(_) => {
+                    self.last_active = Instant::now();
+                    self.request_since.get_or_insert(self.last_active);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn max_input(&self) -> usize {
+        match self.codec {
+            Some(_) => MAX_FRAME_LEN as usize + 4,
+            None => MAX_HANDSHAKE_LEN,
+        }
+    }
+
+    /// Reads what the socket holds and parses it.
+    fn read_and_parse(&mut self) -> Result<()> {
+        let answered = self.codec.is_some();
+        self.read()?;
+        self.parse()?;
+        // the reads stop short of the longest frame until the handshake is answered
+        if !answered && self.codec.is_some() {
+            self.read()?;
+            self.parse()?;
+        }
+        Ok(())
+    }
+
+    /// Parses the handshake, then the requests, out of the bytes read so far.
+    fn parse(&mut self) -> Result<()> {
+        if self.closing {
+            return Ok(());
+        }
+        let len = self.input.len();
+        let codec = match self.codec {
+            Some(codec) => codec,
+            None => {
+                // not all there yet
+                let Some(offset) = self.handshake.scan(&self.input)? else {
+                    return Ok(());
+                };
+                let client: Handshake = serde_json::from_slice(&self.input[..offset])?;
+                self.input.drain(..offset);
+                let (handshake, codec) = server::answer_handshake(&client, self.peer_addr);
+                serde_json::to_writer(&mut self.output, &handshake)?;
+                let Some(codec) = codec else {
+                    self.closing = true;
+                    return Ok(());
+                };
+                self.codec = Some(codec);
+                codec
+            }
+        };
+        let mut parsed = 0;
+        // a frame is only read once it has arrived in full
+        while let Some(len) = self.input.get(parsed..parsed + 4) {
+            let len = u32::from_be_bytes(len.try_into().unwrap());
+            if len <= MAX_FRAME_LEN && self.input.len() - parsed - 4 < len as usize {
+                break;
+            }
+            let mut frame = &self.input[parsed..];
+            if let Some(req) = codec.read(&mut frame)? {
+                self.requests.push_back(req);
+            }
+            parsed = self.input.len() - frame.len();
+        }
+        self.input.drain(..parsed);
+        // what is left arrived with the last read at the earliest
+        if self.input.len() < len {
+            self.request_since = (!self.input.is_empty()).then_some(self.last_active);
+        }
+        Ok(())
+    }
+
+    /// Writes as much of the output as the socket takes.
+    fn write(&mut self) -> Result<()> {
+        let mut written = 0;
+        while written < self.output.len() {
+            match self.stream.write(&self.output[written..]) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(len) => {
+                    written += len;
+                    self.last_active = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.output.drain(..written);
+        Ok(())
+    }
+
+    /// Tells whether there is nothing left to do on the connection.
+    fn is_done(&self) -> bool {
+        self.output.is_empty()
+            && (self.closing || (self.read_closed && !self.busy && self.requests.is_empty()))
+    }
+}
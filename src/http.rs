@@ -0,0 +1,474 @@
+//! An HTTP/1.1 gateway answering in JSON, so that web services and `curl` can
+//! reach a `KvsServer`.
+//!
+//! - `GET /kv/{key}` returns the value of a key as the body.
+//! - `PUT /kv/{key}` sets a key to the body. A `ttl` query parameter in
+//!   seconds makes it expire.
+//! - `DELETE /kv/{key}` removes a key.
+//! - `GET /kv?prefix=&after=&limit=` lists the keys starting with `prefix` with
+//!   their values, as a JSON array of `{"key": ..., "value": ...}` objects. It
+//!   returns 100 of them unless `limit` asks for up to 1000, starting past the
+//!   key `after` if given, so the next page starts after the last key of the
+//!   previous one. A key or a value that is not UTF-8 is base64-encoded in a
+//!   `key_base64` or `value_base64` field instead. The records the memcached front end
+//!   keeps with its values are left out.
+//! - `GET /health` tells that the server is up.
+//! - `GET /metrics` returns the counters of the server for Prometheus.
+//!
+//! Keys are percent-decoded from the path, so they can hold any bytes. Errors
+//! are JSON objects with an `error` field.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::{debug, error, info};
+use serde_json::json;
+
+use crate::codec::MAX_FRAME_LEN;
+use crate::engines::KvsEngine;
+use crate::memcached;
+use crate::metrics::Metrics;
+use crate::server::{Protocol, TimedReader, Timeouts};
+use crate::{KvsError, Result};
+
+// the request line and every header line must fit in this
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_LEN: usize = MAX_FRAME_LEN as usize;
+// number of keys `GET /kv` lists without a limit, and the largest limit
+const DEFAULT_PAGE_LEN: u64 = 100;
+const MAX_PAGE_LEN: u64 = 1000;
+
+/// Serves the HTTP requests of a connection until either side closes it.
+pub(crate) fn serve<E: KvsEngine>(
+    engine: E,
+    tcp: TcpStream,
+    metrics: &Metrics,
+    timeouts: Timeouts,
+) -> Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    info!("Accepted HTTP connection from {}", peer_addr);
+
+    let mut reader = BufReader::new(TimedReader::new(&tcp));
+    let mut writer = BufWriter::new(&tcp);
+
+    while timeouts.wait_for_request(&mut reader)? {
+        let req = match read_request(&mut reader, &mut writer) {
+            Ok(Some(req)) => req,
+            Ok(None) => break,
+            Err(ReadError::Io(e)) => return Err(e.into()),
+            // the rest of the stream cannot be trusted after a malformed request
+            Err(ReadError::Invalid(resp)) => {
+                metrics.request(Protocol::Http, false);
+                resp.write_to(&mut writer, true)?;
+                break;
+            }
+        };
+        debug!("Receive HTTP request from {}: {} {}", peer_addr, req.method, req.path);
+        let close = req.close;
+        let resp = handle(&engine, metrics, req);
+        metrics.request(Protocol::Http, resp.status < 400);
+        resp.write_to(&mut writer, close)?;
+        if close {
+            break;
+        }
+        // responses to pipelined requests are sent together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Turns a connection down because too many are open.
+pub(crate) fn reject<W: Write>(writer: &mut W) -> Result<()> {
+    HttpResponse::error(503, "Too many connections").write_to(writer, true)?;
+    Ok(())
+}
+
+fn handle<E: KvsEngine>(engine: &E, metrics: &Metrics, req: HttpRequest) -> HttpResponse {
+    if let Some(key) = req.path.strip_prefix("/kv/") {
+        let key = match percent_decode(key, false) {
+            Some(key) => key,
+            None => return HttpResponse::error(400, "Invalid percent-encoding in the key"),
+        };
+        let result = match req.method.as_str() {
+            "GET" => engine.get_bytes(key).map(|value| match value {
+                Some(value) => HttpResponse::new(200, "application/octet-stream", value),
+                None => HttpResponse::error(404, "Key not found"),
+            }),
+            "PUT" => match req.param("ttl").map(|ttl| parse(&ttl)) {
+                None => engine.set_bytes(key, req.body),
+                Some(Some(ttl)) => {
+                    engine.set_bytes_with_ttl(key, req.body, Duration::from_secs(ttl))
+                }
+                Some(None) => return HttpResponse::error(400, "Invalid ttl"),
+            }
+            .map(|_| HttpResponse::empty()),
+            "DELETE" => engine
+                .remove_bytes(key.clone())
+                .and_then(|_| memcached::forget(engine, &key))
+                .map(|_| HttpResponse::empty()),
+            _ => return HttpResponse::method_not_allowed("GET, PUT, DELETE"),
+        };
+        return result.unwrap_or_else(engine_error);
+    }
+
+    match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/kv") => {
+            let prefix = req.param("prefix").unwrap_or_default();
+            let limit = match req.param("limit").map(|limit| parse(&limit)) {
+                None => DEFAULT_PAGE_LEN as usize,
+                Some(Some(limit)) if limit <= MAX_PAGE_LEN => limit as usize,
+                Some(Some(_)) => {
+                    let msg = format!("The limit must not exceed {}", MAX_PAGE_LEN);
+                    return HttpResponse::error(400, &msg);
+                }
+                Some(None) => return HttpResponse::error(400, "Invalid limit"),
+            };
+            let scan = match req.param("after") {
+                // the keys with the prefix all come after a smaller key
+                Some(after) if after >= prefix => {
+                    engine.scan_bytes((Bound::Excluded(after), Bound::Unbounded), None)
+                }
+                _ => engine.scan_prefix_bytes(prefix.clone(), None),
+            };
+            let entries = scan.and_then(|scan| {
+                scan.take_while(|pair| match pair {
+                    Ok((key, _)) => key.starts_with(&prefix),
+                    Err(_) => true,
+                })
+                // the records memcached keeps with its values are not listed
+                .filter(|pair| !matches!(pair, Ok((key, _)) if memcached::is_internal(key)))
+                .take(limit)
+                .collect::<Result<Vec<_>>>()
+            });
+            match entries {
+                Ok(entries) => {
+                    let entries = entries
+                        .into_iter()
+                        .map(|(key, value)| {
+                            let mut entry = serde_json::Map::new();
+                            insert_bytes(&mut entry, "key", key);
+                            insert_bytes(&mut entry, "value", value);
+                            serde_json::Value::Object(entry)
+                        })
+                        .collect();
+                    HttpResponse::json(200, serde_json::Value::Array(entries))
+                }
+                Err(e) => engine_error(e),
+            }
+        }
+        ("GET", "/health") => HttpResponse::json(200, json!({ "status": "ok" })),
+        ("GET", "/metrics") => HttpResponse::new(
+            200,
+            "text/plain; version=0.0.4",
+            metrics.render().into_bytes(),
+        ),
+        (_, "/kv" | "/health" | "/metrics") => HttpResponse::method_not_allowed("GET"),
+        _ => HttpResponse::error(404, "Not found"),
+    }
+}
+
+/// Adds `bytes` to a JSON object as a string, or base64-encoded under
+/// `{name}_base64` if they are not UTF-8.
+fn insert_bytes(
+    object: &mut serde_json::Map<String, serde_json::Value>,
+    name: &str,
+    bytes: Vec<u8>,
+) {
+    match String::from_utf8(bytes) {
+        Ok(s) => object.insert(name.to_owned(), s.into()),
+        Err(e) => object.insert(format!("{}_base64", name), BASE64.encode(e.as_bytes()).into()),
+    };
+}
+
+fn engine_error(e: KvsError) -> HttpResponse {
+    let status = match e {
+        KvsError::KeyNotFound => 404,
+        KvsError::Conflict => 409,
+        KvsError::ReadOnly => 403,
+        KvsError::Utf8Error(_) => 400,
+        _ => {
+            error!("HTTP request failed: {}", e);
+            500
+        }
+    };
+    HttpResponse::error(status, &e.to_string())
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: String,
+    body: Vec<u8>,
+    // whether the connection is closed after the response
+    close: bool,
+}
+
+impl HttpRequest {
+    /// Returns the decoded value of a query parameter.
+    fn param(&self, name: &str) -> Option<Vec<u8>> {
+        self.query.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match percent_decode(key, true) {
+                Some(key) if key == name.as_bytes() => percent_decode(value, true),
+                _ => None,
+            }
+        })
+    }
+}
+
+struct HttpResponse {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    // only sent with 405 responses
+    allow: Option<&'static str>,
+}
+
+impl HttpResponse {
+    fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> HttpResponse {
+        HttpResponse {
+            status,
+            content_type,
+            body,
+            allow: None,
+        }
+    }
+
+    fn empty() -> HttpResponse {
+        HttpResponse::new(204, "", Vec::new())
+    }
+
+    fn json(status: u16, value: serde_json::Value) -> HttpResponse {
+        HttpResponse::new(status, "application/json", value.to_string().into_bytes())
+    }
+
+    fn error(status: u16, msg: &str) -> HttpResponse {
+        HttpResponse::json(status, json!({ "error": msg }))
+    }
+
+    fn method_not_allowed(allow: &'static str) -> HttpResponse {
+        HttpResponse {
+            allow: Some(allow),
+            ..HttpResponse::error(405, "Method not allowed")
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, close: bool) -> io::Result<()> {
+        write!(writer, "HTTP/1.1 {} {}\r\n", self.status, reason(self.status))?;
+        // a 204 response has no body, not even an empty one
+        if self.status != 204 {
+            write!(writer, "Content-Type: {}\r\n", self.content_type)?;
+            write!(writer, "Content-Length: {}\r\n", self.body.len())?;
+        }
+        if let Some(allow) = self.allow {
+            write!(writer, "Allow: {}\r\n", allow)?;
+        }
+        if close {
+            writer.write_all(b"Connection: close\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&self.body)
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Why a request cannot be read.
+enum ReadError {
+    Io(io::Error),
+    // the response to send before closing the connection
+    Invalid(HttpResponse),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(err: io::Error) -> ReadError {
+        ReadError::Io(err)
+    }
+}
+
+fn invalid(status: u16, msg: &str) -> ReadError {
+    ReadError::Invalid(HttpResponse::error(status, msg))
+}
+
+/// Reads the next request with its body.
+///
+/// Returns `None` if the connection is closed between requests.
+fn read_request<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+) -> std::result::Result<Option<HttpRequest>, ReadError> {
+    // empty lines before a request are ignored
+    let line = loop {
+        match read_line(reader)? {
+            None => return Ok(None),
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+        }
+    };
+    let line = String::from_utf8(line).map_err(|_| invalid(400, "Invalid request line"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    let (method, target, version) = match parts[..] {
+        [method, target, version] => (method, target, version),
+        _ => return Err(invalid(400, "Invalid request line")),
+    };
+    let mut close = match version {
+        "HTTP/1.1" => false,
+        "HTTP/1.0" => true,
+        _ => return Err(invalid(505, "HTTP version not supported")),
+    };
+
+    let mut content_len = None;
+    let mut chunked = false;
+    let mut expect_continue = false;
+    let mut headers = 0;
+    loop {
+        let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        if line.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(invalid(431, "Too many headers"));
+        }
+        let line = String::from_utf8_lossy(&line);
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid(400, "Invalid header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                let len = value.parse().map_err(|_| invalid(400, "Invalid Content-Length"))?;
+                content_len = Some(len);
+            }
+            "transfer-encoding" => match value.to_ascii_lowercase().as_str() {
+                "chunked" => chunked = true,
+                _ => return Err(invalid(501, "Transfer encoding not supported")),
+            },
+            "connection" => match value.to_ascii_lowercase().as_str() {
+                "close" => close = true,
+                "keep-alive" => close = false,
+                _ => {}
+            },
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => {}
+        }
+    }
+
+    if content_len.is_some_and(|len| len > MAX_BODY_LEN) {
+        return Err(invalid(413, "Body too large"));
+    }
+    // the client waits for this before sending a large body
+    if expect_continue && (chunked || content_len.is_some_and(|len| len > 0)) {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        writer.flush()?;
+    }
+    let body = if chunked {
+        read_chunked(reader)?
+    } else {
+        let mut body = vec![0; content_len.unwrap_or(0)];
+        reader.read_exact(&mut body)?;
+        body
+    };
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Ok(Some(HttpRequest {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query: query.to_owned(),
+        body,
+        close,
+    }))
+}
+
+/// Reads a body sent in chunks.
+fn read_chunked<R: BufRead>(reader: &mut R) -> std::result::Result<Vec<u8>, ReadError> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        // chunk extensions after a `;` are ignored
+        let size = String::from_utf8_lossy(&line);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid(400, "Invalid chunk size"))?;
+        if size == 0 {
+            // skip the trailers
+            while !read_line(reader)?.ok_or_else(unexpected_eof)?.is_empty() {}
+            return Ok(body);
+        }
+        // the size comes from the client, so the sum could overflow
+        if size > MAX_BODY_LEN - body.len() {
+            return Err(invalid(413, "Body too large"));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        if !read_line(reader)?.ok_or_else(unexpected_eof)?.is_empty() {
+            return Err(invalid(400, "Chunk not terminated by CRLF"));
+        }
+    }
+}
+
+/// Reads a line without its line break.
+fn read_line<R: BufRead>(reader: &mut R) -> std::result::Result<Option<Vec<u8>>, ReadError> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid(431, "Line too long or not terminated"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn unexpected_eof() -> ReadError {
+    ReadError::Io(io::ErrorKind::UnexpectedEof.into())
+}
+
+fn parse(bytes: &[u8]) -> Option<u64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+/// Decodes the `%XX` escapes of a URL component, and `+` as a space in a
+/// query string.
+fn percent_decode(s: &str, plus_as_space: bool) -> Option<Vec<u8>> {
+    let mut bytes = s.bytes();
+    let mut decoded = Vec::with_capacity(s.len());
+    while let Some(byte) = bytes.next() {
+        decoded.push(match byte {
+            b'%' => {
+                let high = (bytes.next()? as char).to_digit(16)?;
+                let low = (bytes.next()? as char).to_digit(16)?;
+                (high * 16 + low) as u8
+            }
+            b'+' if plus_as_space => b' ',
+            _ => byte,
+        });
+    }
+    Some(decoded)
+}
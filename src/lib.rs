@@ -1,11 +1,36 @@
 pub mod engines;
 pub mod client;
+pub mod client_pool;
 pub mod server;
+#[cfg(feature = "async")]
+pub mod async_client;
+#[cfg(feature = "async")]
+pub mod async_server;
 mod error;
 mod common;
+mod codec;
+mod resp;
+mod http;
+mod memcached;
+mod metrics;
+mod shutdown;
+mod event_loop;
 pub mod thread_pool;
 
-pub use error::{Result, KvsError};
-pub use self::engines::{KvStore, KvsEngine, SledKvsEngine};
-pub use self::client::KvsClient;
-pub use self::server::KvsServer;
+pub use error::{ErrorCode, Result, KvsError};
+pub use self::engines::{
+    BatchOp, ByteScan, KvStore, KvStoreOptions, KvStoreSnapshot, KvsEngine, KvsSnapshot, Scan,
+    SledKvsEngine, SledSnapshot, SyncPolicy, Transaction, WriteBatch,
+};
+pub use self::client::{KvsClient, Pipeline};
+pub use self::client_pool::{KvsClientPool, KvsClientPoolOptions, PooledClient};
+#[cfg(feature = "async")]
+pub use self::async_client::AsyncKvsClient;
+#[cfg(feature = "async")]
+pub use self::async_server::AsyncKvsServer;
+#[cfg(feature = "async")]
+pub use self::engines::AsyncEngine;
+pub use self::codec::Encoding;
+pub use self::common::PROTOCOL_VERSION;
+pub use self::server::{KvsServer, Protocol};
+pub use self::shutdown::ShutdownHandle;
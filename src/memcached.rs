@@ -0,0 +1,533 @@
+//! A memcached ASCII protocol front end, so that memcached clients can talk to
+//! a `KvsServer`.
+//!
+//! It supports `get`, `gets`, `set`, `add`, `replace`, `cas`, `delete`,
+//! `incr`, `decr`, `version` and `quit`.
+//!
+//! A value is stored in the engine as it is, so the other protocols see it too,
+//! and the engine expires it. Its flags, expiration time and version are kept
+//! in a record under the key with `META_PREFIX` in front, which is written in
+//! the same atomic write as the value and which the other front ends leave out
+//! of their listings. The record holds a hash of the value it belongs to, so a
+//! value written with another protocol is served with no flags.
+//!
+//! The cas token `gets` returns is the version of the value, which every write
+//! of this front end bumps. A value written with another protocol has none,
+//! and gets a hash of its bytes instead.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+
+use log::{debug, info};
+
+use crate::codec::MAX_FRAME_LEN;
+use crate::engines::expiry::now_millis;
+use crate::engines::{KvsEngine, WriteBatch};
+use crate::metrics::Metrics;
+use crate::server::{Protocol, TimedReader, Timeouts};
+use crate::KvsError;
+
+const MAX_LINE_LEN: u64 = 2048;
+const MAX_KEY_LEN: usize = 250;
+// an expiration time over 30 days is a Unix time rather than a number of seconds
+const MAX_RELATIVE_EXPTIME: i64 = 30 * 24 * 60 * 60;
+/// Prefix of the keys holding the records kept with the values written by this
+/// front end.
+const META_PREFIX: &[u8] = b"\0memcached\0";
+// set in the cas tokens of values written by other protocols, and in no version
+const FOREIGN_TOKEN: u64 = 1 << 63;
+
+/// Tells whether `key` holds a record of this front end rather than a value.
+pub(crate) fn is_internal(key: &[u8]) -> bool {
+    key.starts_with(META_PREFIX)
+}
+
+/// Removes the record kept with the value at `key`, if it has one, once
+/// another front end has removed the value.
+pub(crate) fn forget<E: KvsEngine>(engine: &E, key: &[u8]) -> crate::Result<()> {
+    match engine.remove_bytes(meta_key(key)) {
+        Ok(()) | Err(KvsError::KeyNotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Serves the memcached commands of a connection until the client closes it.
+pub(crate) fn serve<E: KvsEngine>(
+    engine: E,
+    tcp: TcpStream,
+    metrics: &Metrics,
+    timeouts: Timeouts,
+) -> crate::Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    info!("Accepted memcached connection from {}", peer_addr);
+
+    let mut reader = BufReader::new(TimedReader::new(&tcp));
+    let mut writer = BufWriter::new(&tcp);
+
+    while timeouts.wait_for_request(&mut reader)? {
+        let Some(line) = read_line(&mut reader)? else {
+            break;
+        };
+        let line = String::from_utf8_lossy(&line).into_owned();
+        let args: Vec<&str> = line.split_ascii_whitespace().collect();
+        let Some((&name, args)) = args.split_first() else {
+            writer.write_all(b"ERROR\r\n")?;
+            continue;
+        };
+        debug!("Receive memcached command from {}: {}", peer_addr, name);
+        if name == "quit" {
+            break;
+        }
+        // a trailing `noreply` asks for no reply, except from retrievals
+        let (args, noreply) = match args.split_last() {
+            Some((&"noreply", args)) if name != "get" && name != "gets" => (args, true),
+            _ => (args, false),
+        };
+        let result = execute(&engine, &mut reader, name, args);
+        metrics.request(Protocol::Memcached, result.is_ok());
+        let reply = match result {
+            Ok(reply) => reply,
+            Err(Error::Unknown) => b"ERROR\r\n".to_vec(),
+            // the data block of a storage command is mistaken for a command
+            // once the command line is wrong, so the connection is closed
+            Err(Error::Client(msg)) if is_storage(name) => {
+                write!(writer, "CLIENT_ERROR {}\r\n", msg)?;
+                break;
+            }
+            Err(Error::Client(msg)) => format!("CLIENT_ERROR {}\r\n", msg).into_bytes(),
+            Err(Error::Server(KvsError::Io(e))) => return Err(KvsError::Io(e)),
+            Err(Error::Server(e)) => format!("SERVER_ERROR {}\r\n", e).into_bytes(),
+        };
+        if !noreply {
+            writer.write_all(&reply)?;
+        }
+        // replies to pipelined commands are sent together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Turns a connection down with the error memcached sends when too many
+/// clients are connected.
+pub(crate) fn reject<W: Write>(writer: &mut W) -> crate::Result<()> {
+    writer.write_all(b"SERVER_ERROR Too many open connections\r\n")?;
+    Ok(())
+}
+
+/// Why a command failed, which tells how the failure is reported.
+enum Error {
+    // reported as `ERROR`
+    Unknown,
+    // reported as `CLIENT_ERROR`
+    Client(&'static str),
+    // reported as `SERVER_ERROR`
+    Server(KvsError),
+}
+
+impl From<KvsError> for Error {
+    fn from(err: KvsError) -> Error {
+        Error::Server(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Server(err.into())
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn is_storage(name: &str) -> bool {
+    matches!(name, "set" | "add" | "replace" | "cas")
+}
+
+/// Runs a command and returns its reply.
+fn execute<E: KvsEngine, R: BufRead>(
+    engine: &E,
+    reader: &mut R,
+    name: &str,
+    args: &[&str],
+) -> Result<Vec<u8>> {
+    let reply = match (name, args) {
+        ("get" | "gets", keys) if !keys.is_empty() => {
+            let mut reply = Vec::new();
+            for key in keys {
+                let Some(item) = Slot::load(engine, &parse_key(key)?)?.item() else {
+                    continue;
+                };
+                write!(reply, "VALUE {} {} {}", key, item.flags, item.data.len())?;
+                if name == "gets" {
+                    write!(reply, " {}", item.token())?;
+                }
+                reply.extend_from_slice(b"\r\n");
+                reply.extend_from_slice(&item.data);
+                reply.extend_from_slice(b"\r\n");
+            }
+            reply.extend_from_slice(b"END\r\n");
+            return Ok(reply);
+        }
+        ("set" | "add" | "replace" | "cas", [key, flags, exptime, len, rest @ ..]) => {
+            let mode = match (name, rest) {
+                ("set", []) => Mode::Set,
+                ("add", []) => Mode::Add,
+                ("replace", []) => Mode::Replace,
+                ("cas", [unique]) => Mode::Cas(parse(unique)?),
+                _ => return Err(Error::Client("bad command line format")),
+            };
+            let (key, flags, exptime) = (parse_key(key)?, parse(flags)?, parse(exptime)?);
+            let data = read_data(reader, parse(len)?)?;
+            store(engine, mode, key, flags, data, expires_at(exptime))?
+        }
+        ("delete", [key]) => delete(engine, parse_key(key)?)?,
+        ("incr" | "decr", [key, delta]) => {
+            let delta = parse(delta)?;
+            match incr(engine, parse_key(key)?, name == "incr", delta)? {
+                Some(value) => return Ok(format!("{}\r\n", value).into_bytes()),
+                None => "NOT_FOUND",
+            }
+        }
+        ("version", []) => {
+            return Ok(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes());
+        }
+        ("get" | "gets" | "set" | "add" | "replace" | "cas" | "delete" | "incr" | "decr", _)
+        | ("version", _) => return Err(Error::Client("bad command line format")),
+        _ => return Err(Error::Unknown),
+    };
+    Ok(format!("{}\r\n", reply).into_bytes())
+}
+
+/// The condition of a storage command.
+#[derive(Clone, Copy)]
+enum Mode {
+    Set,
+    // only if the key is missing
+    Add,
+    // only if the key exists
+    Replace,
+    // only if the key has not been written since `gets` returned this token
+    Cas(u64),
+}
+
+/// Stores `data` with `flags` at `key` if `mode` allows it, and returns the
+/// reply.
+///
+/// `expires_at` is in milliseconds since the Unix epoch.
+fn store<E: KvsEngine>(
+    engine: &E,
+    mode: Mode,
+    key: Vec<u8>,
+    flags: u32,
+    data: Vec<u8>,
+    expires_at: Option<u64>,
+) -> Result<&'static str> {
+    loop {
+        let slot = Slot::load(engine, &key)?;
+        match (mode, slot.item()) {
+            (Mode::Add, Some(_)) | (Mode::Replace, None) => return Ok("NOT_STORED"),
+            (Mode::Cas(_), None) => return Ok("NOT_FOUND"),
+            (Mode::Cas(unique), Some(old)) if old.token() != unique => return Ok("EXISTS"),
+            _ => {}
+        }
+        let mut batch = WriteBatch::new();
+        match expires_at {
+            // already expired, so it is stored as gone
+            Some(expires_at) if expires_at <= now_millis() => {
+                batch.remove(key.clone()).remove(meta_key(&key));
+            }
+            _ => {
+                let item = Item {
+                    flags,
+                    data: data.clone(),
+                    expires_at,
+                    version: Some(slot.next_version()),
+                };
+                item.write_to(&mut batch, &key);
+            }
+        }
+        if slot.replace(engine, &key, batch)? {
+            return Ok("STORED");
+        }
+    }
+}
+
+/// Removes the value at `key` and its record, and returns the reply.
+fn delete<E: KvsEngine>(engine: &E, key: Vec<u8>) -> Result<&'static str> {
+    loop {
+        let slot = Slot::load(engine, &key)?;
+        if slot.data.is_none() {
+            return Ok("NOT_FOUND");
+        }
+        let mut batch = WriteBatch::new();
+        batch.remove(key.clone()).remove(meta_key(&key));
+        if slot.replace(engine, &key, batch)? {
+            return Ok("DELETED");
+        }
+    }
+}
+
+/// Adds `delta` to the decimal value at `key`, or subtracts it down to 0, and
+/// returns the new value.
+///
+/// Returns `None` if the key does not exist.
+fn incr<E: KvsEngine>(engine: &E, key: Vec<u8>, up: bool, delta: u64) -> Result<Option<u64>> {
+    loop {
+        let slot = Slot::load(engine, &key)?;
+        let Some(item) = slot.item() else {
+            return Ok(None);
+        };
+        let value: u64 = std::str::from_utf8(&item.data)
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or(Error::Client(
+                "cannot increment or decrement non-numeric value",
+            ))?;
+        // like memcached, an increment wraps around and a decrement stops at 0
+        let value = if up {
+            value.wrapping_add(delta)
+        } else {
+            value.saturating_sub(delta)
+        };
+        let data = value.to_string().into_bytes();
+        let swapped = match item.version {
+            // the value keeps its flags and expiration time
+            Some(_) => {
+                let new = Item {
+                    data,
+                    version: Some(slot.next_version()),
+                    ..item
+                };
+                let mut batch = WriteBatch::new();
+                new.write_to(&mut batch, &key);
+                slot.replace(engine, &key, batch)?
+            }
+            // the expiration time of a value written with another protocol is
+            // only known to the engine, which keeps it
+            None => {
+                engine.compare_and_swap_bytes_with_ttl(key.clone(), Some(item.data), data, None)?
+            }
+        };
+        if swapped {
+            return Ok(Some(value));
+        }
+    }
+}
+
+fn meta_key(key: &[u8]) -> Vec<u8> {
+    [META_PREFIX, key].concat()
+}
+
+/// The value at a key and the record kept with it, as read together.
+struct Slot {
+    data: Option<Vec<u8>>,
+    meta: Option<Vec<u8>>,
+}
+
+impl Slot {
+    /// Reads the value at `key` and its record.
+    fn load<E: KvsEngine>(engine: &E, key: &[u8]) -> Result<Slot> {
+        loop {
+            let data = engine.get_bytes(key.to_vec())?;
+            let meta = engine.get_bytes(meta_key(key))?;
+            let slot = Slot { data, meta };
+            // A record that does not belong to the value was either left by a
+            // value another protocol has overwritten, or written along with a
+            // value written after the one read, which is read again then.
+            if slot.item().is_none_or(|item| item.version.is_some())
+                || slot.meta.is_none()
+                || slot.data == engine.get_bytes(key.to_vec())?
+            {
+                return Ok(slot);
+            }
+        }
+    }
+
+    /// Returns the item stored, if any.
+    fn item(&self) -> Option<Item> {
+        let data = self.data.clone()?;
+        let meta = self.meta.as_deref().and_then(Meta::decode);
+        Some(match meta {
+            Some(meta) if meta.data_hash == hash(&data) => Item {
+                flags: meta.flags,
+                data,
+                expires_at: meta.expires_at,
+                version: Some(meta.version),
+            },
+            // written with another protocol
+            _ => Item {
+                flags: 0,
+                data,
+                expires_at: None,
+                version: None,
+            },
+        })
+    }
+
+    /// Returns the version of the item replacing the one read.
+    ///
+    /// The versions of a key go on from the record left by a value another
+    /// protocol has overwritten. A key without a record starts from the clock,
+    /// so that a key removed and stored again hands out no token it has
+    /// handed out before.
+    fn next_version(&self) -> u64 {
+        match self.meta.as_deref().and_then(Meta::decode) {
+            Some(meta) => meta.version + 1,
+            None => now_millis() * 1000,
+        }
+    }
+
+    /// Applies `batch` to the engine unless the value or its record has been
+    /// written since they were read, and returns whether it was applied.
+    fn replace<E: KvsEngine>(&self, engine: &E, key: &[u8], batch: WriteBatch) -> Result<bool> {
+        let expected = vec![
+            (key.to_vec(), self.data.clone()),
+            (meta_key(key), self.meta.clone()),
+        ];
+        Ok(engine.write_if(expected, batch)?)
+    }
+}
+
+/// A value with what memcached keeps with it.
+struct Item {
+    flags: u32,
+    data: Vec<u8>,
+    // in milliseconds since the Unix epoch
+    expires_at: Option<u64>,
+    // none if the value was written with another protocol
+    version: Option<u64>,
+}
+
+impl Item {
+    /// Returns the cas token of the item.
+    fn token(&self) -> u64 {
+        match self.version {
+            Some(version) => version,
+            None => hash(&self.data) | FOREIGN_TOKEN,
+        }
+    }
+
+    /// Adds writing the item and its record at `key` to `batch`.
+    fn write_to(&self, batch: &mut WriteBatch, key: &[u8]) {
+        let meta = Meta {
+            flags: self.flags,
+            version: self.version.unwrap_or_default(),
+            expires_at: self.expires_at,
+            data_hash: hash(&self.data),
+        };
+        // the record expires with the value
+        match self.expires_at {
+            None => batch
+                .set(key, self.data.clone())
+                .set(meta_key(key), meta.encode()),
+            Some(expires_at) => batch
+                .set_expiring_at(key, self.data.clone(), expires_at)
+                .set_expiring_at(meta_key(key), meta.encode(), expires_at),
+        };
+    }
+}
+
+/// The record kept with a value written by this front end.
+///
+/// It is encoded as big-endian integers, with an expiration time of 0 for a
+/// value that does not expire.
+struct Meta {
+    flags: u32,
+    version: u64,
+    expires_at: Option<u64>,
+    // tells the value the record belongs to
+    data_hash: u64,
+}
+
+impl Meta {
+    const LEN: usize = 4 + 8 + 8 + 8;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut record = Vec::with_capacity(Meta::LEN);
+        record.extend_from_slice(&self.flags.to_be_bytes());
+        record.extend_from_slice(&self.version.to_be_bytes());
+        record.extend_from_slice(&self.expires_at.unwrap_or(0).to_be_bytes());
+        record.extend_from_slice(&self.data_hash.to_be_bytes());
+        record
+    }
+
+    fn decode(record: &[u8]) -> Option<Meta> {
+        if record.len() != Meta::LEN {
+            return None;
+        }
+        let u64_at = |at: usize| u64::from_be_bytes(record[at..at + 8].try_into().unwrap());
+        Some(Meta {
+            flags: u32::from_be_bytes(record[..4].try_into().unwrap()),
+            version: u64_at(4),
+            expires_at: Some(u64_at(12)).filter(|&expires_at| expires_at != 0),
+            data_hash: u64_at(20),
+        })
+    }
+}
+
+/// Hashes bytes with FNV-1a, which unlike the hashers of the standard library
+/// gives the same result whatever the build.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Converts a memcached expiration time to milliseconds since the Unix epoch.
+fn expires_at(exptime: i64) -> Option<u64> {
+    match exptime {
+        0 => None,
+        // a negative time means already expired
+        exptime if exptime < 0 => Some(1),
+        exptime if exptime <= MAX_RELATIVE_EXPTIME => Some(now_millis() + exptime as u64 * 1000),
+        exptime => Some(exptime as u64 * 1000),
+    }
+}
+
+/// Reads the data block of a storage command.
+fn read_data<R: BufRead>(reader: &mut R, len: usize) -> Result<Vec<u8>> {
+    if len > MAX_FRAME_LEN as usize {
+        return Err(Error::Client("object too large for cache"));
+    }
+    let mut data = vec![0; len + 2];
+    reader.read_exact(&mut data)?;
+    if !data.ends_with(b"\r\n") {
+        return Err(Error::Client("bad data chunk"));
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Reads a command line without its line break.
+fn read_line<R: BufRead>(reader: &mut R) -> crate::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(KvsError::StringError("Command line too long".to_owned()));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_key(key: &str) -> Result<Vec<u8>> {
+    // keys are split on whitespace, so they cannot hold any
+    if key.len() > MAX_KEY_LEN || key.bytes().any(|byte| byte.is_ascii_control()) {
+        return Err(Error::Client("bad key"));
+    }
+    Ok(key.as_bytes().to_vec())
+}
+
+fn parse<T: std::str::FromStr>(arg: &str) -> Result<T> {
+    arg.parse()
+        .map_err(|_| Error::Client("bad command line format"))
+}
@@ -0,0 +1,108 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::server::Protocol;
+
+/// Counters of a `KvsServer`, exposed by the `/metrics` endpoint of the HTTP
+/// gateway in the Prometheus text format.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    // indexed by protocol
+    connections: [AtomicU64; Protocol::ALL.len()],
+    active_connections: [AtomicU64; Protocol::ALL.len()],
+    rejected_connections: [AtomicU64; Protocol::ALL.len()],
+    requests: [AtomicU64; Protocol::ALL.len()],
+    failed_requests: [AtomicU64; Protocol::ALL.len()],
+}
+
+impl Metrics {
+    /// Counts a new connection, which stays active until the guard is dropped.
+    pub(crate) fn connection(self: &Arc<Self>, protocol: Protocol) -> ConnectionGuard {
+        self.connections[protocol as usize].fetch_add(1, Ordering::Relaxed);
+        self.active_connections[protocol as usize].fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            metrics: Arc::clone(self),
+            protocol,
+        }
+    }
+
+    /// Counts a connection turned down because too many are open.
+    pub(crate) fn rejected_connection(&self, protocol: Protocol) {
+        self.rejected_connections[protocol as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of connections open over every protocol.
+    pub(crate) fn active_connections(&self) -> u64 {
+        self.active_connections
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Counts a request served, `ok` telling whether it succeeded.
+    pub(crate) fn request(&self, protocol: Protocol, ok: bool) {
+        self.requests[protocol as usize].fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.failed_requests[protocol as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Renders the counters in the Prometheus text format.
+    pub(crate) fn render(&self) -> String {
+        let families = [
+            (
+                "kvs_connections_total",
+                "counter",
+                "Connections accepted.",
+                &self.connections,
+            ),
+            (
+                "kvs_active_connections",
+                "gauge",
+                "Connections currently open.",
+                &self.active_connections,
+            ),
+            (
+                "kvs_rejected_connections_total",
+                "counter",
+                "Connections turned down because too many were open.",
+                &self.rejected_connections,
+            ),
+            (
+                "kvs_requests_total",
+                "counter",
+                "Requests served.",
+                &self.requests,
+            ),
+            (
+                "kvs_failed_requests_total",
+                "counter",
+                "Requests answered with an error.",
+                &self.failed_requests,
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, values) in families {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            for &protocol in Protocol::ALL {
+                let value = values[protocol as usize].load(Ordering::Relaxed);
+                let _ = writeln!(text, "{}{{protocol=\"{}\"}} {}", name, protocol, value);
+            }
+        }
+        text
+    }
+}
+
+/// Counts a connection as active while it is alive.
+pub(crate) struct ConnectionGuard {
+    metrics: Arc<Metrics>,
+    protocol: Protocol,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics.active_connections[self.protocol as usize].fetch_sub(1, Ordering::Relaxed);
+    }
+}
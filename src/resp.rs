@@ -0,0 +1,485 @@
+//! A RESP2 front end, so that Redis clients and tools can talk to a `KvsServer`.
+//!
+//! Commands are mapped onto `KvsEngine`:
+//!
+//! - `GET`, `SET` (with `EX` or `PX`), `DEL` and `EXISTS` use the plain engine
+//!   operations.
+//! - `INCR` retries a compare-and-swap until it wins. The key keeps its TTL.
+//! - `EXPIRE` retries a compare-and-swap with a TTL until it wins, so that a
+//!   concurrent write is never lost.
+//! - `SCAN` walks the keys in order. Its cursors stand for the last key
+//!   returned and are only valid on the connection that got them. The records
+//!   the memcached front end keeps with its values are left out.
+//! - `PING` and `QUIT` behave as in Redis.
+//!
+//! Other commands are answered with an error.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::codec::MAX_FRAME_LEN;
+use crate::engines::KvsEngine;
+use crate::memcached;
+use crate::metrics::Metrics;
+use crate::server::{Protocol, TimedReader, Timeouts};
+use crate::{KvsError, Result};
+
+// Limits on what a client may send, mirroring the ones of Redis.
+const MAX_INLINE_LEN: u64 = 64 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+// cursors kept per connection, the oldest ones are forgotten first
+const MAX_CURSORS: usize = 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Turns a connection down with the error Redis sends when too many clients
+/// are connected.
+pub(crate) fn reject<W: Write>(writer: &mut W) -> Result<()> {
+    Reply::Error("ERR max number of clients reached".to_owned()).write_to(writer)
+}
+
+/// Serves the RESP commands of a connection until the client closes it.
+pub(crate) fn serve<E: KvsEngine>(
+    engine: E,
+    tcp: TcpStream,
+    metrics: &Metrics,
+    timeouts: Timeouts,
+) -> Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    info!("Accepted RESP connection from {}", peer_addr);
+
+    let mut reader = BufReader::new(TimedReader::new(&tcp));
+    let mut writer = BufWriter::new(&tcp);
+    let mut session = Session {
+        engine,
+        cursors: BTreeMap::new(),
+        next_cursor: 1,
+    };
+
+    while timeouts.wait_for_request(&mut reader)? {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(KvsError::Io(e)) => return Err(KvsError::Io(e)),
+            // like Redis, report a malformed request and hang up
+            Err(e) => {
+                Reply::Error(format!("ERR {}", e)).write_to(&mut writer)?;
+                break;
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&args[0]);
+        debug!("Receive RESP command from {}: {}", peer_addr, name);
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = match session.execute(args) {
+            Ok(reply) => reply,
+            Err(e) => Reply::Error(format!("ERR {}", e)),
+        };
+        metrics.request(Protocol::Resp, !matches!(reply, Reply::Error(_)));
+        reply.write_to(&mut writer)?;
+        if quit {
+            break;
+        }
+        // replies to pipelined commands are sent together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// A RESP2 reply.
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            Reply::Status(status) => write!(writer, "+{}\r\n", status)?,
+            // a line break would end the error early
+            Reply::Error(err) => write!(writer, "-{}\r\n", err.replace(['\r', '\n'], " "))?,
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            Reply::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")?;
+            }
+            Reply::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                for reply in replies {
+                    reply.write_to(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The state of a RESP connection.
+struct Session<E: KvsEngine> {
+    engine: E,
+    // maps a SCAN cursor to the last key returned with it
+    cursors: BTreeMap<u64, Vec<u8>>,
+    next_cursor: u64,
+}
+
+impl<E: KvsEngine> Session<E> {
+    /// Runs a command, `args` holding its name and then its arguments.
+    fn execute(&mut self, mut args: Vec<Vec<u8>>) -> Result<Reply> {
+        let name = String::from_utf8_lossy(&args.remove(0)).to_ascii_lowercase();
+        let arity = |valid: bool| {
+            if valid {
+                Ok(())
+            } else {
+                Err(error(format!("wrong number of arguments for '{}' command", name)))
+            }
+        };
+        match name.as_str() {
+            "ping" => {
+                arity(args.len() <= 1)?;
+                Ok(match args.pop() {
+                    Some(msg) => Reply::Bulk(Some(msg)),
+                    None => Reply::Status("PONG"),
+                })
+            }
+            "quit" => Ok(Reply::Status("OK")),
+            "get" => {
+                arity(args.len() == 1)?;
+                Ok(Reply::Bulk(self.engine.get_bytes(args.remove(0))?))
+            }
+            "set" => {
+                arity(args.len() >= 2)?;
+                self.set(args)
+            }
+            "del" => {
+                arity(!args.is_empty())?;
+                let mut removed = 0;
+                for key in args {
+                    match self.engine.remove_bytes(key.clone()) {
+                        Ok(()) => {
+                            memcached::forget(&self.engine, &key)?;
+                            removed += 1;
+                        }
+                        Err(KvsError::KeyNotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(Reply::Integer(removed))
+            }
+            "exists" => {
+                arity(!args.is_empty())?;
+                let mut found = 0;
+                for key in args {
+                    if self.engine.get_bytes(key)?.is_some() {
+                        found += 1;
+                    }
+                }
+                Ok(Reply::Integer(found))
+            }
+            "incr" => {
+                arity(args.len() == 1)?;
+                self.incr(args.remove(0))
+            }
+            "expire" => {
+                arity(args.len() == 2)?;
+                let seconds = parse_int(&args[1])?;
+                self.expire(args.remove(0), seconds)
+            }
+            "scan" => {
+                arity(!args.is_empty())?;
+                self.scan(args)
+            }
+            _ => Err(error(format!("unknown command '{}'", name))),
+        }
+    }
+
+    fn set(&mut self, args: Vec<Vec<u8>>) -> Result<Reply> {
+        let mut args = args.into_iter();
+        let (key, value) = (args.next().unwrap(), args.next().unwrap());
+        let mut ttl = None;
+        while let Some(option) = args.next() {
+            let to_duration: fn(u64) -> Duration = match option.to_ascii_uppercase().as_slice() {
+                b"EX" => Duration::from_secs,
+                b"PX" => Duration::from_millis,
+                _ => return Err(error("syntax error")),
+            };
+            let amount = args.next().ok_or_else(|| error("syntax error"))?;
+            match parse_int(&amount)? {
+                amount if amount > 0 && ttl.is_none() => ttl = Some(to_duration(amount as u64)),
+                _ => return Err(error("invalid expire time in 'set' command")),
+            }
+        }
+        match ttl {
+            Some(ttl) => self.engine.set_bytes_with_ttl(key, value, ttl)?,
+            None => self.engine.set_bytes(key, value)?,
+        }
+        Ok(Reply::Status("OK"))
+    }
+
+    fn incr(&mut self, key: Vec<u8>) -> Result<Reply> {
+        loop {
+            let current = self.engine.get_bytes(key.clone())?;
+            let n = match &current {
+                Some(value) => parse_int(value)?,
+                None => 0,
+            };
+            let n = n.checked_add(1).ok_or_else(|| error("increment or decrement would overflow"))?;
+            // as in Redis, the key keeps its time to live
+            let new = n.to_string().into_bytes();
+            if self.engine.compare_and_swap_bytes_with_ttl(key.clone(), current, new, None)? {
+                return Ok(Reply::Integer(n));
+            }
+        }
+    }
+
+    fn expire(&mut self, key: Vec<u8>, seconds: i64) -> Result<Reply> {
+        // a deadline in the past removes the key right away
+        if seconds <= 0 {
+            return match self.engine.remove_bytes(key.clone()) {
+                Ok(()) => {
+                    memcached::forget(&self.engine, &key)?;
+                    Ok(Reply::Integer(1))
+                }
+                Err(KvsError::KeyNotFound) => Ok(Reply::Integer(0)),
+                Err(e) => Err(e),
+            };
+        }
+        let ttl = Some(Duration::from_secs(seconds as u64));
+        loop {
+            let Some(value) = self.engine.get_bytes(key.clone())? else {
+                return Ok(Reply::Integer(0));
+            };
+            let current = Some(value.clone());
+            if self.engine.compare_and_swap_bytes_with_ttl(key.clone(), current, value, ttl)? {
+                return Ok(Reply::Integer(1));
+            }
+        }
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`
+    ///
+    /// As in Redis, `COUNT` is the number of keys looked at, of which only the
+    /// ones matching the pattern are returned.
+    fn scan(&mut self, args: Vec<Vec<u8>>) -> Result<Reply> {
+        let mut args = args.into_iter();
+        let cursor = args.next().unwrap();
+        let start = match parse_int(&cursor).map_err(|_| error("invalid cursor"))? {
+            0 => Bound::Unbounded,
+            cursor => {
+                let cursor = u64::try_from(cursor).map_err(|_| error("invalid cursor"))?;
+                let key = self.cursors.remove(&cursor).ok_or_else(|| error("invalid cursor"))?;
+                Bound::Excluded(key)
+            }
+        };
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        while let Some(option) = args.next() {
+            let value = args.next().ok_or_else(|| error("syntax error"))?;
+            match option.to_ascii_uppercase().as_slice() {
+                b"MATCH" => pattern = Some(value),
+                b"COUNT" => match parse_int(&value)? {
+                    n if n > 0 => count = n as usize,
+                    _ => return Err(error("syntax error")),
+                },
+                _ => return Err(error("syntax error")),
+            }
+        }
+
+        let keys = self
+            .engine
+            .scan_bytes((start, Bound::Unbounded), Some(count))?
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?;
+        // a short page means the scan is over
+        let next = match keys.last() {
+            Some(last) if keys.len() == count => self.save_cursor(last.clone()),
+            _ => 0,
+        };
+        let keys = keys
+            .into_iter()
+            // the records memcached keeps with its values are looked at but not listed
+            .filter(|key| !memcached::is_internal(key))
+            .filter(|key| pattern.as_ref().is_none_or(|pattern| glob_match(pattern, key)))
+            .map(|key| Reply::Bulk(Some(key)))
+            .collect();
+        Ok(Reply::Array(vec![
+            Reply::Bulk(Some(next.to_string().into_bytes())),
+            Reply::Array(keys),
+        ]))
+    }
+
+    fn save_cursor(&mut self, key: Vec<u8>) -> u64 {
+        if self.cursors.len() >= MAX_CURSORS {
+            let oldest = *self.cursors.keys().next().unwrap();
+            self.cursors.remove(&oldest);
+        }
+        let cursor = self.next_cursor;
+        self.next_cursor += 1;
+        self.cursors.insert(cursor, key);
+        cursor
+    }
+}
+
+/// Reads the next command, either a RESP array of bulk strings or an inline
+/// command as typed in a telnet session.
+///
+/// Returns `None` if the connection is closed between commands.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = match line.split_first() {
+        Some((b'*', count)) => parse_len(count, MAX_ARGS)?,
+        _ => {
+            let args = line
+                .split(|byte| byte.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            return Ok(Some(args));
+        }
+    };
+    let mut args = Vec::with_capacity(count.min(16));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| protocol_error("unexpected end of stream"))?;
+        let len = match line.split_first() {
+            Some((b'$', len)) => parse_len(len, MAX_FRAME_LEN as usize)?,
+            _ => return Err(protocol_error("expected a bulk string")),
+        };
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line without its line break.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.take(MAX_INLINE_LEN).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(protocol_error("line too long or not terminated"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn parse_int(bytes: &[u8]) -> Result<i64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| error("value is not an integer or out of range"))
+}
+
+fn error(msg: impl Into<String>) -> KvsError {
+    KvsError::StringError(msg.into())
+}
+
+fn protocol_error(msg: &str) -> KvsError {
+    KvsError::StringError(format!("Protocol error: {}", msg))
+}
+
+/// Matches `key` against a glob-style pattern the way Redis does: `*` and `?`
+/// match any bytes, `[...]` a class of bytes and `\` escapes the next byte.
+///
+/// A mismatch only backtracks to the last `*`, which makes more bytes match
+/// it, so the time taken is bounded by the length of the pattern times the
+/// length of the key whatever the pattern is.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // where the pattern goes on after the last `*`, and where the key does after what it matched
+    let mut star = None;
+    while k < key.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, k));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], key[k]) {
+            p += len;
+            k += 1;
+            continue;
+        }
+        match star {
+            Some((after, start)) => {
+                p = after;
+                k = start + 1;
+                star = Some((after, k));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+/// Matches `byte` against the element at the start of `pattern`, which is not
+/// a `*`, and returns the length of the element if it matches.
+fn match_one(pattern: &[u8], byte: u8) -> Option<usize> {
+    let (matched, rest) = match pattern {
+        [] => return None,
+        [b'?', rest @ ..] => (true, rest),
+        [b'[', class @ ..] => match_class(class, byte),
+        [b'\\', escaped, rest @ ..] | [escaped, rest @ ..] => (*escaped == byte, rest),
+    };
+    matched.then_some(pattern.len() - rest.len())
+}
+
+/// Matches `byte` against the class at the start of `pattern`, right after
+/// its `[`, and returns the pattern after the class.
+fn match_class(pattern: &[u8], byte: u8) -> (bool, &[u8]) {
+    let (negated, mut pattern) = match pattern {
+        [b'^', rest @ ..] => (true, rest),
+        _ => (false, pattern),
+    };
+    let mut matched = false;
+    loop {
+        pattern = match pattern {
+            // an unterminated class ends with the pattern
+            [] => return (matched != negated, pattern),
+            [b']', rest @ ..] => return (matched != negated, rest),
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == byte;
+                rest
+            }
+            [low, b'-', high, rest @ ..] if *high != b']' => {
+                matched |= (*low.min(high)..=*low.max(high)).contains(&byte);
+                rest
+            }
+            [other, rest @ ..] => {
+                matched |= *other == byte;
+                rest
+            }
+        };
+    }
+}
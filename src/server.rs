@@ -1,86 +1,577 @@
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::iter;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use log::{error, info, debug};
-use serde_json::Deserializer;
+use log::{error, info, debug, warn};
 
-use crate::common::{Request, SetResponse, RemoveResponse, GetResponse};
-use crate::engines::KvsEngine;
+use crate::codec::{Codec, Encoding};
+use crate::common::{Handshake, Request, Tagged, Response, FEATURES, PROTOCOL_VERSION};
+use crate::engines::{KvsEngine, Transaction};
+use crate::event_loop::EventLoopHandle;
+use crate::metrics::Metrics;
+use crate::shutdown::{Connections, ShutdownHandle};
 use crate::thread_pool::ThreadPool;
-use crate::{Result};
+use crate::{http, memcached, resp};
+use crate::{KvsError, Result};
+
+/// The protocol a `KvsServer` speaks with its clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// The protocol of `KvsClient`, the default.
+    Kvs,
+    /// RESP2, for Redis clients and tools.
+    Resp,
+    /// HTTP with JSON, for web services and `curl`.
+    Http,
+    /// The memcached ASCII protocol, for memcached clients.
+    Memcached,
+}
+
+impl Protocol {
+    /// Every protocol.
+    pub const ALL: &'static [Protocol] =
+        &[Protocol::Kvs, Protocol::Resp, Protocol::Http, Protocol::Memcached];
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Kvs => write!(f, "kvs"),
+            Protocol::Resp => write!(f, "resp"),
+            Protocol::Http => write!(f, "http"),
+            Protocol::Memcached => write!(f, "memcached"),
+        }
+    }
+}
+
+/// How long a server shutting down waits for its connections by default.
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a rejected connection is given to hang up after reading the error.
+const REJECT_LINGER: Duration = Duration::from_secs(1);
+/// How much a rejected connection may send before it is closed regardless.
+const MAX_REJECT_DISCARD: u64 = 64 * 1024;
+/// How many rejected connections may wait for their error before more are
+/// closed without one.
+const MAX_PENDING_REJECTS: usize = 64;
+
+/// The read and write timeouts of the connections of a server.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Timeouts {
+    // how long a connection may wait before sending its next request
+    pub(crate) idle: Option<Duration>,
+    // how long sending a request in full, or reading a response, may take
+    pub(crate) request: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Waits for the next request on the connection read by `reader`, then
+    /// gives the request the request timeout to arrive in full.
+    ///
+    /// Returns false if the connection stayed idle for too long, or is closed.
+    pub(crate) fn wait_for_request(&self, reader: &mut BufReader<TimedReader>) -> Result<bool> {
+        let tcp = reader.get_ref().tcp;
+        if reader.buffer().is_empty() {
+            reader.get_mut().deadline = None;
+            tcp.set_read_timeout(self.idle)?;
+            match reader.fill_buf() {
+                Ok([]) => return Ok(false),
+                Ok(_) => {}
+                Err(e) if is_timeout(&e) => {
+                    info!("Closing connection from {}: idle for too long", tcp.peer_addr()?);
+                    return Ok(false);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        tcp.set_read_timeout(self.request)?;
+        reader.get_mut().deadline = self.request.map(|timeout| Instant::now() + timeout);
+        Ok(true)
+    }
+}
+
+/// Reads a connection, failing once the request being read is past its deadline.
+///
+/// A read timeout alone would only bound each read, which a client sending
+/// its request a byte at a time could keep from ever expiring.
+pub(crate) struct TimedReader<'a> {
+    tcp: &'a TcpStream,
+    // when the request being read times out, none while waiting for one
+    deadline: Option<Instant>,
+}
+
+impl<'a> TimedReader<'a> {
+    pub(crate) fn new(tcp: &'a TcpStream) -> Self {
+        TimedReader { tcp, deadline: None }
+    }
+}
+
+impl Read for TimedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.tcp.set_read_timeout(Some(left))?;
+        }
+        self.tcp.read(buf)
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    is_timeout_kind(err.kind())
+}
+
+fn is_timeout_kind(kind: io::ErrorKind) -> bool {
+    // reads and writes timing out fail with either kind depending on the platform
+    matches!(kind, io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+// Whether serving a connection failed because a read or a write timed out.
+fn timed_out(err: &KvsError) -> bool {
+    match err {
+        KvsError::Io(e) => is_timeout(e),
+        // the handshake is read by the JSON deserializer
+        KvsError::Serde(e) => e.io_error_kind().is_some_and(is_timeout_kind),
+        _ => false,
+    }
+}
 
 pub struct KvsServer<E: KvsEngine, T: ThreadPool> {
     engine: E,
     thread_pool: T,
+    protocol: Protocol,
+    // listeners added with `listen`
+    listeners: Vec<(TcpListener, Protocol)>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    max_connections: Option<u64>,
+    timeouts: Timeouts,
+    // event loops serving the kvs protocol, none to serve it from the pool
+    io_threads: usize,
 }
 
 impl<E: KvsEngine, T: ThreadPool>  KvsServer<E, T> {
     pub fn new(engine: E, thread_pool: T) -> Self {
-        KvsServer { engine, thread_pool }
+        KvsServer {
+            engine,
+            thread_pool,
+            protocol: Protocol::Kvs,
+            listeners: Vec::new(),
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            io_threads: 0,
+        }
     }
 
-    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
+    /// Sets the protocol spoken on the address passed to `run`.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Also serves `protocol` on `addr`, with the same engine and thread pool.
+    ///
+    /// The address is bound right away, so that it is known to be usable
+    /// before the server runs.
+    pub fn listen<A: ToSocketAddrs>(mut self, addr: A, protocol: Protocol) -> Result<Self> {
+        self.listeners.push((TcpListener::bind(addr)?, protocol));
+        Ok(self)
+    }
+
+    /// Sets how long the server waits, once asked to shut down, for the open
+    /// connections to answer the requests they have sent before closing them.
+    ///
+    /// It is 30 seconds by default.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
 
+    /// Sets how many connections may be open at once over every protocol.
+    ///
+    /// A connection over the limit is sent an error its client understands and
+    /// closed. A connection waiting for a thread of the pool counts as open, so
+    /// a limit no greater than the number of threads keeps every client served.
+    /// There is no limit by default.
+    pub fn max_connections(mut self, max: u64) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Closes the connections that send no request for `timeout`.
+    ///
+    /// The timeout must not be zero. Idle connections are kept open by default.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero(), "the idle timeout must not be zero");
+        self.timeouts.idle = Some(timeout);
+        self
+    }
+
+    /// Closes the connections taking longer than `timeout` to send a request in
+    /// full once it has begun, or to read a response.
+    ///
+    /// The timeout must not be zero. There is no timeout by default.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero(), "the request timeout must not be zero");
+        self.timeouts.request = Some(timeout);
+        self
+    }
 
-        for stream in listener.incoming() {
-            let engine = self.engine.clone();
-            self.thread_pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(e) = serve(engine, stream) {
-                        error!("Error on serving client: {}", e);
+    /// Serves the connections of the kvs protocol from `io_threads` event loops
+    /// rather than from a thread of the pool each.
+    ///
+    /// The event loops read and write every connection without blocking, and
+    /// only run the requests on the thread pool, so that a few threads serve
+    /// thousands of mostly idle clients. The other protocols are still served
+    /// from the pool. The number of threads must not be zero.
+    pub fn event_loops(mut self, io_threads: usize) -> Self {
+        assert!(io_threads > 0, "the number of I/O threads must not be zero");
+        self.io_threads = io_threads;
+        self
+    }
+
+    /// Returns a handle to shut the server down once it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves the clients until the server is shut down through a
+    /// `ShutdownHandle`.
+    ///
+    /// It returns once the connections are drained and the engine is flushed.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()>
+    where
+        T: Sync,
+    {
+        let listener = TcpListener::bind(addr)?;
+        let metrics = Arc::new(Metrics::default());
+        let connections = Arc::new(Connections::default());
+
+        // Every listener accepts on a thread of its own and hands the
+        // connections over to the thread pool here.
+        let (sender, receiver) = mpsc::channel();
+        for (listener, protocol) in iter::once((listener, self.protocol)).chain(self.listeners) {
+            self.shutdown.add_listener(listener.local_addr()?);
+            let sender = sender.clone();
+            let shutdown = self.shutdown.clone();
+            thread::spawn(move || {
+                while !shutdown.is_shutdown() {
+                    let stream = listener.accept().map(|(stream, _)| stream);
+                    // the shutdown handle wakes the thread with a connection of its own
+                    if shutdown.is_shutdown() || sender.send((stream, protocol)).is_err() {
+                        break;
                     }
-                },
-                Err(e) => error!("Connection failed: {}", e),
+                }
             });
         }
+        drop(sender);
+
+        // Rejecting lingers until the client hangs up, which the pool has no
+        // thread to spare for, so the rejected connections queue for a thread
+        // of their own. It stops once the server is done with the sender.
+        let (rejecter, rejected) = mpsc::sync_channel(MAX_PENDING_REJECTS);
+        thread::spawn(move || {
+            for (stream, protocol) in rejected {
+                if let Err(e) = reject(stream, protocol) {
+                    debug!("Error on rejecting client: {}", e);
+                }
+            }
+        });
 
+        thread::scope(|scope| -> Result<()> {
+            let event_loops = (0..self.io_threads)
+                .map(|_| EventLoopHandle::spawn(
+                    scope,
+                    self.engine.clone(),
+                    &self.thread_pool,
+                    Arc::clone(&metrics),
+                    self.timeouts,
+                ))
+                .collect::<Result<Vec<_>>>()?;
+            // the connections are spread over the event loops in turn
+            let mut event_loop = event_loops.iter().cycle();
+
+            // the receiver is done once every listener has stopped
+            for (stream, protocol) in receiver {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("Connection failed: {}", e);
+                        continue;
+                    },
+                };
+                if self.max_connections.is_some_and(|max| metrics.active_connections() >= max) {
+                    metrics.rejected_connection(protocol);
+                    if let Ok(peer_addr) = stream.peer_addr() {
+                        warn!("Rejecting connection from {}: too many connections", peer_addr);
+                    }
+                    // dropped, and so closed without an error, if too many are waiting
+                    let _ = rejecter.try_send((stream, protocol));
+                    continue;
+                }
+                // counted here so that the connections waiting for a thread count as open
+                let connection = metrics.connection(protocol);
+                if protocol == Protocol::Kvs {
+                    if let Some(event_loop) = event_loop.next() {
+                        match connections.track(&stream) {
+                            Ok(tracked) => event_loop.add(stream, connection, tracked),
+                            Err(e) => error!("Connection failed: {}", e),
+                        }
+                        continue;
+                    }
+                }
+                let engine = self.engine.clone();
+                let metrics = Arc::clone(&metrics);
+                let connections = Arc::clone(&connections);
+                let timeouts = self.timeouts;
+                self.thread_pool.spawn(move || {
+                    let _connection = connection;
+                    let _tracked = match connections.track(&stream) {
+                        Ok(tracked) => tracked,
+                        Err(e) => {
+                            error!("Connection failed: {}", e);
+                            return;
+                        },
+                    };
+                    let peer_addr = stream.peer_addr();
+                    let result = stream.set_write_timeout(timeouts.request)
+                        .map_err(KvsError::from)
+                        .and_then(|_| match protocol {
+                            Protocol::Kvs => serve(engine, stream, &metrics, timeouts),
+                            Protocol::Resp => resp::serve(engine, stream, &metrics, timeouts),
+                            Protocol::Http => http::serve(engine, stream, &metrics, timeouts),
+                            Protocol::Memcached => {
+                                memcached::serve(engine, stream, &metrics, timeouts)
+                            },
+                        });
+                    match (result, peer_addr) {
+                        (Ok(_), _) => {},
+                        (Err(e), Ok(peer_addr)) if timed_out(&e) => {
+                            warn!("Closing connection from {}: request timed out", peer_addr);
+                        },
+                        (Err(e), _) => error!("Error on serving client: {}", e),
+                    }
+                });
+            }
+
+            info!("Shutting down");
+            connections.drain(self.shutdown_timeout);
+            drop(event_loops);
+            Ok(())
+        })?;
+        // dropping the pool waits for the jobs left
+        drop(self.thread_pool);
+        self.engine.flush()?;
+        info!("Server stopped");
         Ok(())
     }
 }
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
+/// Turns a connection down with an error its client understands.
+fn reject(tcp: TcpStream, protocol: Protocol) -> Result<()> {
+    let mut writer = BufWriter::new(&tcp);
+    match protocol {
+        Protocol::Kvs => {
+            let handshake = Handshake {
+                version: PROTOCOL_VERSION,
+                features: Vec::new(),
+                encodings: Vec::new(),
+                error: Some("Too many connections".to_owned()),
+            };
+            serde_json::to_writer(&mut writer, &handshake)?;
+        },
+        Protocol::Resp => resp::reject(&mut writer)?,
+        Protocol::Http => http::reject(&mut writer)?,
+        Protocol::Memcached => memcached::reject(&mut writer)?,
+    }
+    writer.flush()?;
+    drop(writer);
+    // Closing the connection with requests left unread would reset it, and the
+    // client could lose the error, so the requests are read until it hangs up.
+    tcp.shutdown(Shutdown::Write)?;
+    tcp.set_read_timeout(Some(REJECT_LINGER))?;
+    let _ = io::copy(&mut (&tcp).take(MAX_REJECT_DISCARD), &mut io::sink());
+    Ok(())
+}
+
+fn serve<E: KvsEngine>(
+    engine: E,
+    tcp: TcpStream,
+    metrics: &Metrics,
+    timeouts: Timeouts,
+) -> Result<()> {
     let peer_addr = tcp.peer_addr()?;
     info!("Accepted connection from {}", peer_addr);
 
-    let buf_reader = BufReader::new(&tcp);
+    let mut reader = BufReader::new(TimedReader::new(&tcp));
     let mut writer = BufWriter::new(&tcp);
-    let req_reader = Deserializer::from_reader(buf_reader).into_iter::<Request>();
-
-    macro_rules! send_resp {
-        ($resp:expr) => {
-            {
-                let resp = $resp;
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
-                debug!("Resonse sent to {}: {:?}", peer_addr, resp);
-            }
-        };
+    if !timeouts.wait_for_request(&mut reader)? {
+        return Ok(());
     }
 
-    for req in req_reader {
-        let req = req?;
+    let client = Handshake::read(&mut reader)?;
+    let (handshake, codec) = answer_handshake(&client, peer_addr);
+    serde_json::to_writer(&mut writer, &handshake)?;
+    writer.flush()?;
+    let Some(codec) = codec else {
+        return Ok(());
+    };
+
+    // the transaction begun on this connection, aborted if the client goes away
+    let mut txn: Option<Transaction<E>> = None;
+
+    // requests are handled in the order they arrive
+    while timeouts.wait_for_request(&mut reader)? {
+        let Some(Tagged { id, msg: req }) = codec.read::<_, Tagged<Request>>(&mut reader)? else {
+            break;
+        };
         debug!("Receive request from {}: {:?}", peer_addr, req);
-        match req {
-            Request::Get { key } => {
-                send_resp!(match engine.get(key) {
-                    Ok(value) => GetResponse::Ok(value),
-                    Err(e) => GetResponse::Err(e.to_string()),
-                })
-            },
-            Request::Set { key, value } => {
-                send_resp!(match engine.set(key, value) {
-                    Ok(_) => SetResponse::Ok,
-                    Err(e) => SetResponse::Err(e.to_string())
-                })
-            },
-            Request::Remove { key } => {
-                send_resp!(match engine.remove(key) {
-                    Ok(_) => RemoveResponse::Ok,
-                    Err(e) => RemoveResponse::Err(e.to_string())
-                })
-            },
+        let resp = handle(&engine, &mut txn, req);
+        metrics.request(Protocol::Kvs, !matches!(resp, Response::Err { .. }));
+        let resp = Tagged { id, msg: resp };
+        codec.write(&mut writer, &resp)?;
+        // responses to pipelined requests are sent together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
         }
+        debug!("Resonse sent to {}: {:?}", peer_addr, resp);
     }
     Ok(())
+}
+
+/// Answers the handshake of a client, and returns the codec of the messages
+/// that follow unless the connection is to be closed.
+pub(crate) fn answer_handshake(
+    client: &Handshake,
+    peer_addr: SocketAddr,
+) -> (Handshake, Option<Codec>) {
+    let features = FEATURES.iter()
+        .filter(|feature| client.features.iter().any(|supported| supported == *feature))
+        .map(|feature| feature.to_string())
+        .collect();
+    // the first encoding the client prefers among the ones known here
+    let encoding = client.encodings.iter()
+        .find_map(|encoding| encoding.parse::<Encoding>().ok());
+    let handshake = Handshake {
+        version: PROTOCOL_VERSION,
+        features,
+        encodings: encoding.iter().map(Encoding::to_string).collect(),
+        error: None,
+    };
+    if client.version != PROTOCOL_VERSION {
+        warn!("{} speaks protocol version {}, closing the connection", peer_addr, client.version);
+        return (handshake, None);
+    }
+    if encoding.is_none() {
+        warn!("{} supports none of the encodings, closing the connection", peer_addr);
+    }
+    (handshake, encoding.map(Codec::new))
+}
+
+/// Handles a request of the kvs protocol, within the transaction of the
+/// connection if there is one.
+pub(crate) fn handle<E: KvsEngine>(
+    engine: &E,
+    txn: &mut Option<Transaction<E>>,
+    req: Request,
+) -> Response {
+    match req {
+        Request::Get { key } => {
+            let result = match txn.as_mut() {
+                Some(txn) => txn.get_bytes(key),
+                None => engine.get_bytes(key),
+            };
+            match result {
+                Ok(value) => Response::Value(value),
+                Err(e) => e.into(),
+            }
+        },
+        Request::Set { key, value, ttl } => {
+            let result = match (txn.as_mut(), ttl) {
+                (Some(_), Some(_)) => Err(in_transaction("TTLs are")),
+                (Some(txn), None) => txn.set_bytes(key, value),
+                (None, Some(ttl)) => engine.set_bytes_with_ttl(key, value, ttl),
+                (None, None) => engine.set_bytes(key, value),
+            };
+            match result {
+                Ok(_) => Response::Ok,
+                Err(e) => e.into()
+            }
+        },
+        Request::Remove { key } => {
+            let result = match txn.as_mut() {
+                Some(txn) => txn.remove_bytes(key),
+                // along with the record of a value written with memcached
+                None => engine.remove_bytes(key.clone())
+                    .and_then(|_| memcached::forget(engine, &key)),
+            };
+            match result {
+                Ok(_) => Response::Ok,
+                Err(e) => e.into()
+            }
+        },
+        Request::Batch { batch } => {
+            let result = match txn {
+                Some(_) => Err(in_transaction("Batches are")),
+                None => engine.write(batch),
+            };
+            match result {
+                Ok(_) => Response::Ok,
+                Err(e) => e.into()
+            }
+        },
+        Request::CompareAndSwap { key, expected, new } => {
+            let result = match txn {
+                Some(_) => Err(in_transaction("Compare-and-swap is")),
+                None => engine.compare_and_swap_bytes(key, expected, new),
+            };
+            match result {
+                Ok(swapped) => Response::Swapped(swapped),
+                Err(e) => e.into()
+            }
+        },
+        Request::Begin => {
+            let result = match txn {
+                Some(_) => Err(KvsError::InvalidRequest(TRANSACTION_IN_PROGRESS.to_owned())),
+                None => engine.begin(),
+            };
+            match result {
+                Ok(new_txn) => {
+                    *txn = Some(new_txn);
+                    Response::Ok
+                },
+                Err(e) => e.into()
+            }
+        },
+        Request::Commit => match txn.take().map(Transaction::commit) {
+            Some(Ok(_)) => Response::Ok,
+            Some(Err(KvsError::Conflict)) => Response::Conflict,
+            Some(Err(e)) => e.into(),
+            None => no_transaction().into(),
+        },
+        Request::Abort => match txn.take() {
+            Some(_) => Response::Ok,
+            None => no_transaction().into(),
+        },
+        Request::Ping => Response::Ok,
+    }
+}
+
+const TRANSACTION_IN_PROGRESS: &str = "A transaction is already in progress";
+const NO_TRANSACTION: &str = "No transaction in progress";
+
+fn in_transaction(what: &str) -> KvsError {
+    KvsError::InvalidRequest(format!("{} not supported in a transaction", what))
+}
+
+fn no_transaction() -> KvsError {
+    KvsError::InvalidRequest(NO_TRANSACTION.to_owned())
 }
\ No newline at end of file
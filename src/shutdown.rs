@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+/// Stops a running `KvsServer`.
+///
+/// It is returned by `KvsServer::shutdown_handle` and can be cloned and sent to
+/// other threads, such as one waiting for signals.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<State>,
+}
+
+struct State {
+    // addresses of the listeners of the server
+    listeners: Mutex<Vec<SocketAddr>>,
+    requested: AtomicBool,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> ShutdownHandle {
+        ShutdownHandle {
+            state: Arc::new(State {
+                listeners: Mutex::new(Vec::new()),
+                requested: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Asks the server to shut down, without waiting for it.
+    ///
+    /// The server stops accepting connections and lets the open ones answer
+    /// the requests they have sent, then flushes the engine and returns from
+    /// `run`. Asking before the server runs makes `run` return right away.
+    pub fn shutdown(&self) {
+        // the lock keeps a listener from being added in between
+        let listeners = self.state.listeners.lock().unwrap();
+        self.state.requested.store(true, Ordering::SeqCst);
+        // wake the threads blocked accepting connections
+        for &addr in listeners.iter() {
+            let _ = TcpStream::connect(local_addr(addr));
+        }
+    }
+
+    /// Tells whether the server has been asked to shut down.
+    pub fn is_shutdown(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst)
+    }
+
+    /// Adds the address of a listener to wake up on shutdown.
+    ///
+    /// The listener must not accept connections before it has been added.
+    pub(crate) fn add_listener(&self, addr: SocketAddr) {
+        self.state.listeners.lock().unwrap().push(addr);
+    }
+}
+
+// The address to connect to in order to reach a listener bound to `addr`.
+fn local_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+/// The connections a server is serving, which are drained on shutdown.
+#[derive(Default)]
+pub(crate) struct Connections {
+    inner: Mutex<Inner>,
+    // notified whenever a connection ends
+    closed: Condvar,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    streams: HashMap<u64, TcpStream>,
+    draining: bool,
+}
+
+impl Connections {
+    /// Keeps track of a connection until the returned guard is dropped.
+    pub(crate) fn track(self: &Arc<Self>, stream: &TcpStream) -> io::Result<Tracked> {
+        let stream = stream.try_clone()?;
+        let mut inner = self.inner.lock().unwrap();
+        if inner.draining {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.streams.insert(id, stream);
+        Ok(Tracked {
+            connections: Arc::clone(self),
+            id,
+        })
+    }
+
+    /// Stops reading from the connections and waits up to `timeout` for them to
+    /// end, then closes the ones left.
+    ///
+    /// Reads return end of file once the requests already received are read,
+    /// so each connection ends after answering them.
+    pub(crate) fn drain(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut inner = self.inner.lock().unwrap();
+        inner.draining = true;
+        for stream in inner.streams.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        while !inner.streams.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("Closing {} connections still busy", inner.streams.len());
+                for stream in inner.streams.values() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                return;
+            }
+            inner = self.closed.wait_timeout(inner, deadline - now).unwrap().0;
+        }
+    }
+}
+
+/// Untracks a connection when dropped.
+pub(crate) struct Tracked {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut inner = self.connections.inner.lock().unwrap();
+        inner.streams.remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
@@ -1,5 +1,9 @@
 use crate::Result;
 
+/// A pool of threads running jobs.
+///
+/// Dropping a pool waits for the jobs spawned on it to finish, including the
+/// ones still queued.
 pub trait ThreadPool {
     fn new(size: u32) -> Result<Self>
         where Self: Sized;
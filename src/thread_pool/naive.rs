@@ -1,20 +1,34 @@
+use std::mem;
 use std::thread;
 
+use crossbeam::sync::WaitGroup;
+
 use crate::Result;
 use super::ThreadPool;
 
 pub struct NaiveThreadPool {
-
+    // cloned by every job and dropped when it is done
+    jobs: WaitGroup,
 }
 
 
 impl ThreadPool for NaiveThreadPool {
     fn new(_threads: u32) -> Result<Self> {
-        Ok(NaiveThreadPool{})
+        Ok(NaiveThreadPool{ jobs: WaitGroup::new() })
     }
 
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
-        thread::spawn(job);
+        let jobs = self.jobs.clone();
+        thread::spawn(move || {
+            job();
+            drop(jobs);
+        });
+    }
+}
+
+impl Drop for NaiveThreadPool {
+    fn drop(&mut self) {
+        mem::take(&mut self.jobs).wait();
     }
 }
 
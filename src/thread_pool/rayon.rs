@@ -1,3 +1,6 @@
+use std::mem;
+
+use crossbeam::sync::WaitGroup;
 use rayon::ThreadPoolBuilder;
 
 use crate::Result;
@@ -5,7 +8,8 @@ use super::ThreadPool;
 
 pub struct RayonThreadPool {
     pool: rayon::ThreadPool,
-
+    // cloned by every job and dropped when it is done
+    jobs: WaitGroup,
 }
 
 impl ThreadPool for RayonThreadPool {
@@ -16,12 +20,25 @@ impl ThreadPool for RayonThreadPool {
                 .num_threads(size as usize)
                 .build()
                 .unwrap(),
+            jobs: WaitGroup::new(),
         })
     }
 
     fn spawn<F>(&self, func: F)
         where F: FnOnce() + Send + 'static
     {
-        self.pool.install(func);
+        // `install` would run `func` on the pool but block the caller until it returns
+        let jobs = self.jobs.clone();
+        self.pool.spawn(move || {
+            func();
+            drop(jobs);
+        });
+    }
+}
+
+impl Drop for RayonThreadPool {
+    fn drop(&mut self) {
+        // dropping a rayon pool does not wait for the jobs spawned on it
+        mem::take(&mut self.jobs).wait();
     }
 }
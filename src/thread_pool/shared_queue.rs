@@ -89,6 +89,7 @@ impl SharedQueueThreadPool {
 
 impl Drop for SharedQueueThreadPool {
     fn drop(&mut self) {
+        // the shutdown messages are queued after the jobs, so the jobs run first
         for _ in &mut self.workers {
             self.sender.send(Message::Shutdown).expect("send shutdown message");
         }
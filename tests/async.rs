@@ -0,0 +1,193 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AsyncEngine, AsyncKvsClient, AsyncKvsServer, KvStore, KvsClient, KvsEngine, KvsError,
+    KvsServer, WriteBatch,
+};
+use std::io::Write;
+use std::net::TcpListener;
+use std::ops::Bound;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// `AsyncKvsClient` should talk to the blocking `KvsServer`.
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_blocking_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4024";
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(2).unwrap());
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.run(addr));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let mut client = AsyncKvsClient::connect(addr).await.unwrap();
+    assert!(client.supports("transactions"));
+    client
+        .set("key1".to_owned(), "value1".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).await.unwrap(),
+        Some("value1".to_owned())
+    );
+    client.remove("key1".to_owned()).await.unwrap();
+    assert_eq!(client.get("key1".to_owned()).await.unwrap(), None);
+    assert!(matches!(
+        client.remove("key1".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+
+    assert!(client
+        .compare_and_swap("key2".to_owned(), None, Some("value2".to_owned()))
+        .await
+        .unwrap());
+    assert!(!client
+        .compare_and_swap("key2".to_owned(), None, Some("value3".to_owned()))
+        .await
+        .unwrap());
+    client.begin().await.unwrap();
+    client
+        .set("key3".to_owned(), "value3".to_owned())
+        .await
+        .unwrap();
+    client.commit().await.unwrap();
+    assert_eq!(
+        client.get("key3".to_owned()).await.unwrap(),
+        Some("value3".to_owned())
+    );
+
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+}
+
+// The blocking `KvsClient` should talk to `AsyncKvsServer`.
+#[tokio::test(flavor = "multi_thread")]
+async fn blocking_client_async_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4025";
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let server = AsyncKvsServer::new(engine).shutdown_timeout(Duration::from_secs(5));
+    let handle = server.shutdown_handle();
+    let server = tokio::spawn(server.run(addr));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    tokio::task::spawn_blocking(move || {
+        let mut client = KvsClient::connect(addr).unwrap();
+        client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        let mut pipeline = client.pipeline();
+        for i in 0..100 {
+            pipeline.set(format!("key{}", i), format!("value{}", i));
+        }
+        assert!(pipeline.execute().unwrap().iter().all(Result::is_ok));
+        assert_eq!(
+            client.get("key99".to_owned()).unwrap(),
+            Some("value99".to_owned())
+        );
+    })
+    .await
+    .unwrap();
+
+    // many async clients are served at once
+    let tasks: Vec<_> = (0..50)
+        .map(|i| {
+            tokio::spawn(async move {
+                let mut client = AsyncKvsClient::connect(addr).await.unwrap();
+                let key = format!("key{}", i);
+                assert_eq!(
+                    client.get(key.clone()).await.unwrap(),
+                    Some(format!("value{}", i))
+                );
+                client.set(key, "async".to_owned()).await.unwrap();
+                client
+            })
+        })
+        .collect();
+    let mut clients = Vec::new();
+    for task in tasks {
+        clients.push(task.await.unwrap());
+    }
+    assert!(clients[0].begin().await.is_ok());
+
+    handle.shutdown();
+    server.await.unwrap().unwrap();
+    // the open connections are closed
+    assert!(clients[1].get("key1".to_owned()).await.is_err());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key49".to_owned()).unwrap(),
+        Some("async".to_owned())
+    );
+}
+
+// `AsyncKvsClient` should give up on a handshake that never ends, and tell
+// a server that is down apart from other failures.
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_connect_errors() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(br#"{"version":1,"features":["#).unwrap();
+        // the client hangs up once it has read enough
+        while stream.write_all(br#""teleport","#).is_ok() {}
+    });
+    match AsyncKvsClient::connect(addr).await {
+        Err(KvsError::StringError(msg)) => assert!(msg.contains("Handshake exceeds"), "{}", msg),
+        Err(e) => panic!("expected the handshake to be too long, got {}", e),
+        Ok(_) => panic!("expected the handshake to be too long"),
+    }
+    handle.join().unwrap();
+
+    // the listener is gone, so nothing listens on the port any more
+    match AsyncKvsClient::connect(addr).await {
+        Err(KvsError::Unavailable(_)) => {}
+        Err(e) => panic!("expected the server to be unavailable, got {}", e),
+        Ok(_) => panic!("expected the server to be unavailable"),
+    }
+}
+
+// `AsyncEngine` should run the calls of an engine from async code.
+#[tokio::test]
+async fn async_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = AsyncEngine::new(KvStore::open(temp_dir.path()).unwrap());
+    engine
+        .set("key1".to_owned(), "value1".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(
+        engine.get("key1".to_owned()).await.unwrap(),
+        Some("value1".to_owned())
+    );
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.remove(b"key1".to_vec());
+    engine.write(batch).await.unwrap();
+    assert_eq!(
+        engine
+            .scan((Bound::Unbounded, Bound::Unbounded), None)
+            .await
+            .unwrap(),
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+    assert!(matches!(
+        engine.remove("key1".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // several calls made as one
+    engine
+        .run(|engine| {
+            let mut txn = engine.begin()?;
+            txn.set("key3".to_owned(), "value3".to_owned())?;
+            txn.commit()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        engine.get_ref().get("key3".to_owned()).unwrap(),
+        Some("value3".to_owned())
+    );
+    engine.flush().await.unwrap();
+}
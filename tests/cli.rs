@@ -1,10 +1,17 @@
 use assert_cmd::prelude::*;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Encoding, KvStore, KvsClient, KvsClientPool, KvsClientPoolOptions, KvsEngine, KvsError,
+    KvsServer, PROTOCOL_VERSION,
+};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
 use std::process::Command;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
 // `kvs-client` with no args should exit with a non-zero code.
@@ -78,6 +85,13 @@ fn client_cli_invalid_set() {
         .assert()
         .failure();
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key", "value", "--ttl", "soon"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["get", "key", "--unknown-flag"])
@@ -129,6 +143,24 @@ fn client_cli_invalid_subcommand() {
         .failure();
 }
 
+// `kvs-client` should exit with 7 when no server listens on the address.
+#[test]
+fn client_cli_server_down() {
+    let temp_dir = TempDir::new().unwrap();
+    // a port nothing listens on any more
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    match KvsClient::connect(addr) {
+        Err(KvsError::Unavailable(_)) => {}
+        other => panic!("expected the server to be unavailable, got {:?}", other.map(|_| ())),
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key", "--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(7);
+}
+
 // `kvs-client -V` should print the version
 #[test]
 fn client_cli_version() {
@@ -151,6 +183,17 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+#[test]
+fn server_cli_invalid_sync() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--sync", "sometimes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -272,7 +315,7 @@ fn cli_access_server(engine: &str, addr: &str) {
         .args(&["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
+        .code(3)
         .stderr(contains("Key not found"));
 
     Command::cargo_bin("kvs-client")
@@ -335,3 +378,1025 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_compare_and_swap() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    // take the lock
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "lock", "--new", "owner1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    // the lock is already taken
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "lock", "--new", "owner2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4)
+        .stderr(contains("Value mismatch"));
+
+    // only the owner can release it
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "lock", "--expected", "owner2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4)
+        .stderr(contains("Value mismatch"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "lock", "--expected", "owner1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "lock", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn client_transactions() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let mut other = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    // writes are visible to the transaction only until it commits
+    client.begin().unwrap();
+    assert!(matches!(client.begin(), Err(KvsError::InvalidRequest(_))));
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(client.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+    assert_eq!(other.get("key2".to_owned()).unwrap(), None);
+    client.commit().unwrap();
+    assert_eq!(other.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+
+    // another connection changes a key the transaction read
+    client.begin().unwrap();
+    client.get("key1".to_owned()).unwrap();
+    other.set("key1".to_owned(), "other".to_owned()).unwrap();
+    client.set("key3".to_owned(), "value3".to_owned()).unwrap();
+    match client.commit() {
+        Err(KvsError::Conflict) => {}
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+    assert_eq!(client.get("key3".to_owned()).unwrap(), None);
+
+    client.begin().unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    client.abort().unwrap();
+    assert!(matches!(client.commit(), Err(KvsError::InvalidRequest(_))));
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("other".to_owned()));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// A client should get a typed error from a server speaking another protocol version.
+#[test]
+fn client_protocol_mismatch() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut handshake = serde_json::Deserializer::from_reader(&stream).into_iter();
+        let _: serde_json::Value = handshake.next().unwrap().unwrap();
+        stream.write_all(br#"{"version":999,"features":[]}"#).unwrap();
+    });
+
+    match KvsClient::connect(addr) {
+        Err(KvsError::ProtocolMismatch { client, server }) => {
+            assert_eq!(client, PROTOCOL_VERSION);
+            assert_eq!(server, 999);
+        }
+        Err(e) => panic!("expected a protocol mismatch, got {}", e),
+        Ok(_) => panic!("expected a protocol mismatch"),
+    }
+    handle.join().unwrap();
+}
+
+// A client should give up on a handshake that does not end.
+#[test]
+fn client_handshake_too_long() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(br#"{"version":1,"features":["#).unwrap();
+        // the client hangs up once it has read enough
+        while stream.write_all(br#""teleport","#).is_ok() {}
+    });
+
+    match KvsClient::connect(addr) {
+        Err(KvsError::StringError(msg)) => assert!(msg.contains("Handshake exceeds"), "{}", msg),
+        Err(e) => panic!("expected the handshake to be too long, got {}", e),
+        Ok(_) => panic!("expected the handshake to be too long"),
+    }
+    handle.join().unwrap();
+}
+
+// The server should answer the handshake of another protocol version and close the connection.
+#[test]
+fn server_protocol_mismatch() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(br#"{"version":999,"features":["transactions","teleport"]}"#)
+        .unwrap();
+    let mut handshake = serde_json::Deserializer::from_reader(&stream).into_iter();
+    let handshake: serde_json::Value = handshake.next().unwrap().unwrap();
+    assert_eq!(handshake["version"], PROTOCOL_VERSION);
+    assert_eq!(handshake["features"], serde_json::json!(["transactions"]));
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// The client should talk to the server with the encoding it asks for.
+#[test]
+fn client_encodings() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    assert_eq!(KvsClient::connect(addr).unwrap().encoding(), Encoding::Bincode);
+    let large: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+    for &encoding in Encoding::ALL {
+        let mut client = KvsClient::connect_with(addr, &[encoding]).unwrap();
+        assert_eq!(client.encoding(), encoding);
+        let key = encoding.to_string().into_bytes();
+        client.set_bytes(key.clone(), large.clone()).unwrap();
+        assert_eq!(client.get_bytes(key).unwrap(), Some(large.clone()));
+    }
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Pipelined requests should get their results in order, failures included.
+#[test]
+fn client_pipeline() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let mut pipeline = client.pipeline();
+    for i in 0..10000 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    let results = pipeline.execute().unwrap();
+    assert_eq!(results.len(), 10000);
+    assert!(results.into_iter().all(|result| result.unwrap().is_none()));
+
+    let mut pipeline = client.pipeline();
+    pipeline
+        .get("key1".to_owned())
+        .remove("missing".to_owned())
+        .remove("key1".to_owned())
+        .get("key1".to_owned())
+        .get("key9999".to_owned());
+    let results = pipeline.execute().unwrap();
+    assert_eq!(results[0].as_ref().unwrap(), &Some(b"value1".to_vec()));
+    assert!(matches!(results[1], Err(KvsError::KeyNotFound)));
+    assert_eq!(results[2].as_ref().unwrap(), &None);
+    assert_eq!(results[3].as_ref().unwrap(), &None);
+    assert_eq!(results[4].as_ref().unwrap(), &Some(b"value9999".to_vec()));
+
+    // the connection goes on after a pipeline
+    assert_eq!(client.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+    assert!(client.pipeline().execute().unwrap().is_empty());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Sends a command as a RESP array and checks the raw reply.
+fn assert_resp(stream: &mut TcpStream, args: &[&str], expected: &str) {
+    let mut cmd = format!("*{}\r\n", args.len());
+    for arg in args {
+        cmd += &format!("${}\r\n{}\r\n", arg.len(), arg);
+    }
+    stream.write_all(cmd.as_bytes()).unwrap();
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(String::from_utf8_lossy(&reply), expected);
+}
+
+// `kvs-server --protocol resp` should answer Redis commands.
+#[test]
+fn server_resp() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--protocol", "resp"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_resp(&mut stream, &["PING"], "+PONG\r\n");
+    assert_resp(&mut stream, &["SET", "a", "1"], "+OK\r\n");
+    assert_resp(&mut stream, &["GET", "a"], "$1\r\n1\r\n");
+    assert_resp(&mut stream, &["GET", "missing"], "$-1\r\n");
+    assert_resp(&mut stream, &["INCR", "a"], ":2\r\n");
+    assert_resp(&mut stream, &["INCR", "n"], ":1\r\n");
+    assert_resp(&mut stream, &["SET", "s", "x"], "+OK\r\n");
+    assert_resp(
+        &mut stream,
+        &["INCR", "s"],
+        "-ERR value is not an integer or out of range\r\n",
+    );
+    assert_resp(&mut stream, &["EXISTS", "a", "s", "missing"], ":2\r\n");
+    assert_resp(&mut stream, &["DEL", "a", "missing"], ":1\r\n");
+    assert_resp(&mut stream, &["EXPIRE", "s", "100"], ":1\r\n");
+    assert_resp(&mut stream, &["EXPIRE", "missing", "100"], ":0\r\n");
+    assert_resp(&mut stream, &["SET", "t", "v", "PX", "100"], "+OK\r\n");
+    // INCR keeps the TTL of the key
+    assert_resp(&mut stream, &["SET", "c", "1", "PX", "100"], "+OK\r\n");
+    assert_resp(&mut stream, &["INCR", "c"], ":2\r\n");
+    thread::sleep(Duration::from_millis(300));
+    assert_resp(&mut stream, &["GET", "t"], "$-1\r\n");
+    assert_resp(&mut stream, &["GET", "c"], "$-1\r\n");
+    assert_resp(
+        &mut stream,
+        &["GET"],
+        "-ERR wrong number of arguments for 'get' command\r\n",
+    );
+    assert_resp(&mut stream, &["FLY"], "-ERR unknown command 'fly'\r\n");
+
+    for key in ["k1", "k2", "k3"] {
+        assert_resp(&mut stream, &["SET", key, "v"], "+OK\r\n");
+    }
+    assert_resp(
+        &mut stream,
+        &["SCAN", "0", "COUNT", "3"],
+        "*2\r\n$1\r\n1\r\n*3\r\n$2\r\nk1\r\n$2\r\nk2\r\n$2\r\nk3\r\n",
+    );
+    assert_resp(
+        &mut stream,
+        &["SCAN", "1", "COUNT", "3"],
+        "*2\r\n$1\r\n0\r\n*2\r\n$1\r\nn\r\n$1\r\ns\r\n",
+    );
+    assert_resp(
+        &mut stream,
+        &["SCAN", "0", "MATCH", "k[13]"],
+        "*2\r\n$1\r\n0\r\n*2\r\n$2\r\nk1\r\n$2\r\nk3\r\n",
+    );
+    assert_resp(
+        &mut stream,
+        &["SCAN", "0", "MATCH", "*3"],
+        "*2\r\n$1\r\n0\r\n*1\r\n$2\r\nk3\r\n",
+    );
+    // a pattern that backtracks a lot on a long key is matched quickly
+    let long_key = "a".repeat(300);
+    assert_resp(&mut stream, &["SET", &long_key, "v"], "+OK\r\n");
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_resp(
+        &mut stream,
+        &["SCAN", "0", "MATCH", "*a*a*a*a*a*a*a*a*a*a*b"],
+        "*2\r\n$1\r\n0\r\n*0\r\n",
+    );
+    stream.set_read_timeout(None).unwrap();
+    assert_resp(&mut stream, &["DEL", &long_key], ":1\r\n");
+    assert_resp(&mut stream, &["SCAN", "7"], "-ERR invalid cursor\r\n");
+
+    // inline and pipelined commands
+    stream.write_all(b"PING\r\nGET n\r\n").unwrap();
+    let mut reply = [0; 14];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"+PONG\r\n$1\r\n1\r\n");
+
+    assert_resp(&mut stream, &["QUIT"], "+OK\r\n");
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `--resp-addr` should serve the same data over RESP next to the kvs protocol.
+#[test]
+fn server_resp_second_port() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let resp_addr = "127.0.0.1:4013";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--resp-addr", resp_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    let mut stream = TcpStream::connect(resp_addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_resp(&mut stream, &["GET", "key"], "$5\r\nvalue\r\n");
+    assert_resp(&mut stream, &["SET", "key", "other"], "+OK\r\n");
+    assert_eq!(client.get("key".to_owned()).unwrap(), Some("other".to_owned()));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Sends an HTTP request on a new connection and returns the status and the body of the response.
+fn http_request(addr: &str, method: &str, target: &str, body: &[u8]) -> (u16, Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        target,
+        body.len()
+    )
+    .unwrap();
+    stream.write_all(body).unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).unwrap();
+    let head_len = resp.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let status = String::from_utf8_lossy(&resp[9..12]).parse().unwrap();
+    (status, resp[head_len..].to_vec())
+}
+
+// `--http-addr` should serve the keys over HTTP next to the kvs protocol.
+#[test]
+fn server_http() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let http_addr = "127.0.0.1:4015";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--http-addr", http_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    assert_eq!(http_request(http_addr, "PUT", "/kv/a%20b", b"hello"), (204, vec![]));
+    assert_eq!(
+        http_request(http_addr, "GET", "/kv/a%20b", b""),
+        (200, b"hello".to_vec())
+    );
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("a b".to_owned()).unwrap(), Some("hello".to_owned()));
+    assert_eq!(http_request(http_addr, "GET", "/kv/missing", b"").0, 404);
+    assert_eq!(http_request(http_addr, "DELETE", "/kv/missing", b"").0, 404);
+    assert_eq!(http_request(http_addr, "DELETE", "/kv/a%20b", b"").0, 204);
+    assert_eq!(client.get("a b".to_owned()).unwrap(), None);
+
+    for (key, value) in [("p1", "1"), ("p2", "2"), ("q", "3")] {
+        let target = format!("/kv/{}", key);
+        assert_eq!(http_request(http_addr, "PUT", &target, value.as_bytes()).0, 204);
+    }
+    assert_eq!(
+        http_request(http_addr, "GET", "/kv?prefix=p&limit=1", b""),
+        (200, br#"[{"key":"p1","value":"1"}]"#.to_vec())
+    );
+    assert_eq!(
+        http_request(http_addr, "GET", "/kv?prefix=p", b""),
+        (200, br#"[{"key":"p1","value":"1"},{"key":"p2","value":"2"}]"#.to_vec())
+    );
+    assert_eq!(http_request(http_addr, "PUT", "/kv/t?ttl=1", b"v").0, 204);
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(http_request(http_addr, "GET", "/kv/t", b"").0, 404);
+    assert_eq!(http_request(http_addr, "PUT", "/kv/t?ttl=soon", b"v").0, 400);
+    assert_eq!(http_request(http_addr, "POST", "/kv/p1", b"").0, 405);
+    assert_eq!(http_request(http_addr, "GET", "/nowhere", b"").0, 404);
+    assert_eq!(
+        http_request(http_addr, "GET", "/health", b""),
+        (200, br#"{"status":"ok"}"#.to_vec())
+    );
+    let (status, metrics) = http_request(http_addr, "GET", "/metrics", b"");
+    assert_eq!(status, 200);
+    let metrics = String::from_utf8(metrics).unwrap();
+    assert!(metrics.contains("kvs_requests_total{protocol=\"http\"} "));
+    assert!(metrics.contains("kvs_failed_requests_total{protocol=\"http\"} 6\n"));
+
+    // pages go on past a key, and bytes that are not UTF-8 are base64-encoded
+    assert_eq!(http_request(http_addr, "PUT", "/kv/p3", b"\xff").0, 204);
+    assert_eq!(
+        http_request(http_addr, "GET", "/kv?prefix=p&after=p1", b""),
+        (200, br#"[{"key":"p2","value":"2"},{"key":"p3","value_base64":"/w=="}]"#.to_vec())
+    );
+    assert_eq!(
+        http_request(http_addr, "GET", "/kv?prefix=p&after=o&limit=1", b""),
+        (200, br#"[{"key":"p1","value":"1"}]"#.to_vec())
+    );
+    assert_eq!(http_request(http_addr, "GET", "/kv?limit=1001", b"").0, 400);
+
+    // a chunked body, then a second request on the same connection
+    let mut stream = TcpStream::connect(http_addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream
+        .write_all(
+            b"PUT /kv/c HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n\
+              GET /kv/c HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).unwrap();
+    let resp = String::from_utf8(resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 204 No Content\r\n"));
+    assert!(resp.contains("HTTP/1.1 200 OK\r\n"));
+    assert!(resp.ends_with("\r\n\r\nabcde"));
+
+    // a chunk size that would overflow the length of the body is too large
+    let mut stream = TcpStream::connect(http_addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream
+        .write_all(
+            b"PUT /kv/c HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3\r\nabc\r\nffffffffffffffff\r\n",
+        )
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).unwrap();
+    assert!(String::from_utf8(resp).unwrap().starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    assert_eq!(http_request(http_addr, "GET", "/kv/c", b""), (200, b"abcde".to_vec()));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Sends raw memcached commands and checks the raw reply.
+fn assert_memcached(stream: &mut TcpStream, cmd: &str, expected: &str) {
+    stream.write_all(cmd.as_bytes()).unwrap();
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(String::from_utf8_lossy(&reply), expected);
+}
+
+// Returns the cas token `gets` returns for a key.
+fn memcached_unique(stream: &mut TcpStream, key: &str) -> String {
+    write!(stream, "gets {}\r\n", key).unwrap();
+    let mut reply = [0; 64];
+    let len = stream.read(&mut reply).unwrap();
+    let reply = String::from_utf8_lossy(&reply[..len]).into_owned();
+    reply.lines().next().unwrap().rsplit(' ').next().unwrap().to_owned()
+}
+
+// `--memcached-addr` should answer memcached commands next to the kvs protocol.
+#[test]
+fn server_memcached() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4016";
+    let memcached_addr = "127.0.0.1:4017";
+    let resp_addr = "127.0.0.1:4027";
+    let http_addr = "127.0.0.1:4028";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--memcached-addr", memcached_addr])
+        .args(&["--resp-addr", resp_addr, "--http-addr", http_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(memcached_addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_memcached(&mut stream, "get key1\r\n", "END\r\n");
+    assert_memcached(&mut stream, "set key1 42 0 6\r\nvalue1\r\n", "STORED\r\n");
+    assert_memcached(&mut stream, "get key1 key2\r\n", "VALUE key1 42 6\r\nvalue1\r\nEND\r\n");
+    assert_memcached(&mut stream, "add key1 0 0 1\r\nx\r\n", "NOT_STORED\r\n");
+    assert_memcached(&mut stream, "replace key2 0 0 1\r\nx\r\n", "NOT_STORED\r\n");
+    assert_memcached(&mut stream, "add key2 0 0 6\r\nvalue2\r\n", "STORED\r\n");
+
+    // a cas token is good for a single write
+    let unique = memcached_unique(&mut stream, "key1");
+    let cas = format!("cas key1 0 0 3 {}\r\nnew\r\n", unique);
+    assert_memcached(&mut stream, &cas, "STORED\r\n");
+    assert_memcached(&mut stream, &cas, "EXISTS\r\n");
+    assert_memcached(&mut stream, "cas key3 0 0 1 1\r\nx\r\n", "NOT_FOUND\r\n");
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("counter".to_owned(), "10".to_owned()).unwrap();
+    assert_memcached(&mut stream, "incr counter 5\r\n", "15\r\n");
+    assert_memcached(&mut stream, "decr counter 20\r\n", "0\r\n");
+    assert_memcached(&mut stream, "incr missing 1\r\n", "NOT_FOUND\r\n");
+    assert_memcached(
+        &mut stream,
+        "incr key2 1\r\n",
+        "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n",
+    );
+    assert_memcached(&mut stream, "get counter\r\n", "VALUE counter 0 1\r\n0\r\nEND\r\n");
+
+    // other protocols see the value as it is, and their writes drop the flags
+    // and change the cas token
+    assert_memcached(&mut stream, "set key4 7 0 2\r\n10\r\n", "STORED\r\n");
+    assert_memcached(&mut stream, "incr key4 1\r\n", "11\r\n");
+    assert_memcached(&mut stream, "get key4\r\n", "VALUE key4 7 2\r\n11\r\nEND\r\n");
+    assert_eq!(client.get("key4".to_owned()).unwrap(), Some("11".to_owned()));
+    let unique = memcached_unique(&mut stream, "key4");
+    client.set("key4".to_owned(), "12".to_owned()).unwrap();
+    let cas = format!("cas key4 0 0 1 {}\r\nx\r\n", unique);
+    assert_memcached(&mut stream, &cas, "EXISTS\r\n");
+    assert_memcached(&mut stream, "get key4\r\n", "VALUE key4 0 2\r\n12\r\nEND\r\n");
+
+    // a token is not good again once the value is written back
+    assert_memcached(&mut stream, "set key5 1 0 1\r\na\r\n", "STORED\r\n");
+    let unique = memcached_unique(&mut stream, "key5");
+    assert_memcached(&mut stream, "set key5 1 0 1\r\nb\r\n", "STORED\r\n");
+    assert_memcached(&mut stream, "set key5 1 0 1\r\na\r\n", "STORED\r\n");
+    let cas = format!("cas key5 2 0 1 {}\r\nc\r\n", unique);
+    assert_memcached(&mut stream, &cas, "EXISTS\r\n");
+    // nor once the key is removed and stored again
+    let unique = memcached_unique(&mut stream, "key5");
+    assert_memcached(&mut stream, "delete key5\r\n", "DELETED\r\n");
+    assert_memcached(&mut stream, "set key5 1 0 1\r\na\r\n", "STORED\r\n");
+    let cas = format!("cas key5 2 0 1 {}\r\nc\r\n", unique);
+    assert_memcached(&mut stream, &cas, "EXISTS\r\n");
+
+    // the records kept with the values are left out of the listings of other
+    // protocols, and removed with their values
+    let (status, listing) = http_request(http_addr, "GET", "/kv", b"");
+    assert_eq!(status, 200);
+    let listing = String::from_utf8(listing).unwrap();
+    assert!(listing.contains("\"key5\"") && !listing.contains("memcached"), "{}", listing);
+    let mut resp = TcpStream::connect(resp_addr).unwrap();
+    resp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_resp(
+        &mut resp,
+        &["SCAN", "0", "MATCH", "*key5*"],
+        "*2\r\n$1\r\n0\r\n*1\r\n$4\r\nkey5\r\n",
+    );
+    assert_resp(&mut resp, &["DEL", "key5"], ":1\r\n");
+    assert_eq!(client.get_bytes(b"\0memcached\0key5".to_vec()).unwrap(), None);
+
+    assert_memcached(&mut stream, "delete key2 noreply\r\ndelete key2\r\n", "NOT_FOUND\r\n");
+    assert_memcached(&mut stream, "set t 0 1 1\r\nv\r\n", "STORED\r\n");
+    thread::sleep(Duration::from_millis(1500));
+    assert_memcached(&mut stream, "get t\r\n", "END\r\n");
+    assert_memcached(&mut stream, "flush_all\r\n", "ERROR\r\n");
+    assert_memcached(&mut stream, "quit\r\n", "");
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `ShutdownHandle::shutdown` should make `run` return with the engine flushed.
+#[test]
+fn server_shutdown_handle() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4018";
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(2).unwrap())
+        .shutdown_timeout(Duration::from_secs(5));
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+    assert!(handle.is_shutdown());
+
+    // the open connection is closed and no new one is accepted
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(TcpStream::connect(addr).is_err());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+}
+
+// `kvs-server` should shut down gracefully on SIGTERM.
+#[cfg(unix)]
+#[test]
+fn server_sigterm() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4019";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let killed = Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+    assert!(child.wait().unwrap().success());
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+}
+
+// `--max-connections` should turn extra connections down, and the timeouts
+// should close the connections that stall.
+#[test]
+fn server_connection_limits() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4020";
+    let resp_addr = "127.0.0.1:4021";
+    let http_addr = "127.0.0.1:4022";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--resp-addr", resp_addr])
+        .args(&["--http-addr", http_addr, "--max-connections", "1"])
+        .args(&["--idle-timeout", "1", "--request-timeout", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    match KvsClient::connect(addr) {
+        Err(KvsError::Unavailable(_)) => {}
+        other => panic!("expected a rejection, got {:?}", other.map(|_| ())),
+    }
+    let mut stream = TcpStream::connect(resp_addr).unwrap();
+    assert_resp(&mut stream, &["PING"], "-ERR max number of clients reached\r\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(7);
+
+    // the idle connection is closed, which makes room for another
+    thread::sleep(Duration::from_millis(1500));
+    assert!(client.get("key1".to_owned()).is_err());
+
+    // a request sent in part is given up on
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"{\"version\"").unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+    thread::sleep(Duration::from_millis(100));
+
+    // so is one sent a byte at a time, however quick every byte is
+    let mut stream = TcpStream::connect(addr).unwrap();
+    for _ in 0..10 {
+        let _ = stream.write_all(b" ");
+        thread::sleep(Duration::from_millis(200));
+    }
+    stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    match stream.read(&mut [0; 1]) {
+        Ok(0) => {}
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+        Ok(_) => panic!("expected the connection to be closed"),
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    drop(client);
+    thread::sleep(Duration::from_millis(100));
+    let (status, metrics) = http_request(http_addr, "GET", "/metrics", b"");
+    assert_eq!(status, 200);
+    let metrics = String::from_utf8(metrics).unwrap();
+    assert!(metrics.contains("kvs_rejected_connections_total{protocol=\"kvs\"} 2\n"));
+    assert!(metrics.contains("kvs_rejected_connections_total{protocol=\"resp\"} 1\n"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// A server with event loops should serve many more idle clients than it has threads.
+#[test]
+fn server_event_loops() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4023";
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(2).unwrap())
+        .event_loops(2)
+        .idle_timeout(Duration::from_secs(2))
+        .request_timeout(Duration::from_secs(1))
+        .shutdown_timeout(Duration::from_secs(5));
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut clients: Vec<_> = (0..200).map(|_| KvsClient::connect(addr).unwrap()).collect();
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.set(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    for (i, client) in clients.iter_mut().enumerate().rev() {
+        assert_eq!(client.get(format!("key{}", i)).unwrap(), Some(format!("value{}", i)));
+    }
+
+    // pipelined requests are answered in order
+    let mut pipeline = clients[0].pipeline();
+    for i in 0..100 {
+        pipeline.set("key".to_owned(), format!("value{}", i));
+        pipeline.get("key".to_owned());
+    }
+    let results = pipeline.execute().unwrap();
+    for (i, result) in results.chunks(2).enumerate() {
+        assert_eq!(result[1].as_ref().unwrap(), &Some(format!("value{}", i).into_bytes()));
+    }
+
+    // a transaction lasts across requests
+    clients[1].begin().unwrap();
+    clients[1].set("key".to_owned(), "in transaction".to_owned()).unwrap();
+    assert_eq!(clients[2].get("key".to_owned()).unwrap(), Some("value99".to_owned()));
+    clients[1].commit().unwrap();
+    assert_eq!(clients[2].get("key".to_owned()).unwrap(), Some("in transaction".to_owned()));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key0", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value0\n");
+
+    // a request sent in part is given up on
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"{\"version\"").unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    // a handshake that never ends is cut short
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let _ = stream.write_all(b"{\"version\":1,\"features\":[");
+    for _ in 0..4096 {
+        if stream.write_all(b"\"feature\",\"feature\",").is_err() {
+            break;
+        }
+    }
+    stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    match stream.read(&mut [0; 1]) {
+        Ok(0) => {}
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+        Ok(_) => panic!("expected the connection to be closed"),
+    }
+
+    // so is one sent a byte at a time
+    let mut stream = TcpStream::connect(addr).unwrap();
+    for _ in 0..10 {
+        let _ = stream.write_all(b" ");
+        thread::sleep(Duration::from_millis(200));
+    }
+    stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    match stream.read(&mut [0; 1]) {
+        Ok(0) => {}
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+        Ok(_) => panic!("expected the connection to be closed"),
+    }
+
+    // and the idle connections are closed
+    thread::sleep(Duration::from_millis(1500));
+    assert!(clients[3].get("key0".to_owned()).is_err());
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value".to_owned()).unwrap();
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+    assert!(client.get("key1".to_owned()).is_err());
+}
+
+// A client pool should share connections between threads and replace broken ones.
+#[test]
+fn client_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4026";
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(4).unwrap())
+        .idle_timeout(Duration::from_secs(1));
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_secs(1));
+
+    let options = KvsClientPoolOptions::new()
+        .min_connections(2)
+        .max_connections(3)
+        .checkout_timeout(Duration::from_millis(200));
+    let pool = Arc::new(KvsClientPool::connect_with(addr, options).unwrap());
+    assert_eq!(pool.open_connections(), 2);
+    assert_eq!(pool.idle_connections(), 2);
+
+    let threads: Vec<_> = (0..8)
+        .map(|i| {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || {
+                for j in 0..50 {
+                    let mut client = pool.get().unwrap();
+                    client.set(format!("key{}-{}", i, j), j.to_string()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(pool.open_connections(), 3);
+    assert_eq!(pool.get().unwrap().get("key7-49".to_owned()).unwrap(), Some("49".to_owned()));
+
+    // checkouts wait for a connection, then time out
+    let clients: Vec<_> = (0..3).map(|_| pool.get().unwrap()).collect();
+    assert!(matches!(pool.get(), Err(KvsError::Unavailable(_))));
+    drop(clients);
+
+    // a transaction left in progress is aborted
+    let mut client = pool.get().unwrap();
+    client.begin().unwrap();
+    client.set("key".to_owned(), "in transaction".to_owned()).unwrap();
+    drop(client);
+    assert_eq!(pool.get().unwrap().get("key".to_owned()).unwrap(), None);
+
+    // connections closed by the server are replaced
+    thread::sleep(Duration::from_millis(1500));
+    let mut client = pool.get().unwrap();
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(client);
+    assert_eq!(pool.open_connections(), 1);
+
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+    assert!(pool.get().is_err());
+    assert_eq!(pool.open_connections(), 0);
+}
+
+// A client pool should not ping a server that does not support pings.
+#[test]
+fn client_pool_without_ping() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        // a connection replacing this one would be refused
+        drop(listener);
+        let mut handshake = serde_json::Deserializer::from_reader(&stream).into_iter();
+        let _: serde_json::Value = handshake.next().unwrap().unwrap();
+        let handshake = serde_json::json!({
+            "version": PROTOCOL_VERSION,
+            "features": [],
+            "encodings": ["json"],
+        });
+        serde_json::to_writer(&mut stream, &handshake).unwrap();
+        // a ping would fail rather than wait for an answer
+        stream.shutdown(Shutdown::Write).unwrap();
+        // nothing is sent until the client hangs up
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        rest
+    });
+
+    let options = KvsClientPoolOptions::new().min_connections(1).max_connections(1);
+    let pool = KvsClientPool::connect_with(addr, options).unwrap();
+    for _ in 0..2 {
+        assert!(!pool.get().unwrap().supports("ping"));
+    }
+    assert_eq!(pool.open_connections(), 1);
+    drop(pool);
+    assert!(handle.join().unwrap().is_empty());
+}
+
+// A client pool should give up on a server that stops answering once the
+// checkout timeout is over.
+#[test]
+fn client_pool_unresponsive_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done, wait) = mpsc::channel::<()>();
+    let handle = thread::spawn(move || {
+        let (mut first, _) = listener.accept().unwrap();
+        let mut handshake = serde_json::Deserializer::from_reader(&first).into_iter();
+        let _: serde_json::Value = handshake.next().unwrap().unwrap();
+        let handshake = serde_json::json!({
+            "version": PROTOCOL_VERSION,
+            "features": ["ping"],
+            "encodings": ["json"],
+        });
+        serde_json::to_writer(&mut first, &handshake).unwrap();
+        // neither pings nor handshakes are answered from now on
+        let (second, _) = listener.accept().unwrap();
+        let _ = wait.recv();
+        drop((first, second));
+    });
+
+    let options = KvsClientPoolOptions::new()
+        .min_connections(1)
+        .max_connections(1)
+        .checkout_timeout(Duration::from_millis(300));
+    let pool = KvsClientPool::connect_with(addr, options).unwrap();
+    for _ in 0..2 {
+        let start = Instant::now();
+        assert!(matches!(pool.get(), Err(KvsError::Unavailable(_))));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+    assert_eq!(pool.open_connections(), 0);
+    done.send(()).unwrap();
+    handle.join().unwrap();
+}
@@ -1,6 +1,12 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    KvStore, KvStoreOptions, KvsEngine, KvsError, KvsSnapshot, Result, SledKvsEngine, SyncPolicy,
+    WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -127,6 +133,905 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// A damaged record should be reported as corruption instead of being misread.
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    // reopening starts a new log, so the first one is sealed
+    drop(KvStore::open(temp_dir.path())?);
+
+    // flip the last byte of the value in the first log file
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    let mut file = OpenOptions::new().write(true).open(&log)?;
+    file.seek(SeekFrom::Start(len - 1))?;
+    file.write_all(b"X")?;
+    drop(file);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen: 1, .. }) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption is not detected"),
+    }
+}
+
+// A partially written record at the end of the newest log is dropped on open.
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // simulate a crash in the middle of writing a record
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&[42, 0, 0, 0, 1, 2])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A damaged record followed by more data is not a torn write, even in the
+// newest log, so it fails the open instead of being truncated away.
+#[test]
+fn detect_corruption_in_newest_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // flip the last byte of the first record, which is as long as the second
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    let mut file = OpenOptions::new().write(true).open(&log)?;
+    file.seek(SeekFrom::Start(8 + (len - 8) / 2 - 1))?;
+    file.write_all(b"X")?;
+    drop(file);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen: 1, .. }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption is not detected"),
+    }
+    assert_eq!(fs::metadata(&log)?.len(), len);
+
+    let options = KvStoreOptions::new().recover_sealed_logs(true);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(fs::metadata(&log)?.len(), len);
+    Ok(())
+}
+
+// Corruption in a sealed log fails the open unless the caller opts in.
+#[test]
+fn recover_sealed_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    // reopening starts a new log, so the first one is sealed
+    drop(KvStore::open(temp_dir.path())?);
+
+    // damage the last record of the first log
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    let mut file = OpenOptions::new().write(true).open(&log)?;
+    file.seek(SeekFrom::Start(len - 1))?;
+    file.write_all(b"X")?;
+    drop(file);
+
+    let options = KvStoreOptions::new().recover_sealed_logs(true);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Writes are readable and persistent with every sync policy.
+#[test]
+fn sync_policies() -> Result<()> {
+    for policy in &[SyncPolicy::Always, SyncPolicy::EveryNms(10), SyncPolicy::Never] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().sync_policy(*policy);
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        drop(store);
+
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+// A read-only store serves reads and rejects writes without touching the files.
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let files = fs::read_dir(temp_dir.path())?.count();
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), files);
+
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_with(&missing, KvStoreOptions::new().read_only(true)).is_err());
+    assert!(KvStore::open_with(&missing, KvStoreOptions::new().create_if_missing(false)).is_err());
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// Log files are sealed once they reach the configured size.
+#[test]
+fn max_log_file_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_log_file_size(1024)
+        .compaction_threshold(u64::MAX);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let logs = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|entry| entry.metadata().unwrap().len()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    assert!(logs.len() > 1);
+    assert!(logs.iter().all(|&len| len < 1024 + 64));
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Writes racing with background compactions are neither lost nor reverted.
+#[test]
+fn compaction_with_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 0..200 {
+                for key_id in 0..10 {
+                    let key = format!("key{}_{}", thread_id, key_id);
+                    store.set(key, format!("{}", iter)).unwrap();
+                }
+                if iter % 10 == 0 {
+                    store.remove(format!("key{}_0", thread_id)).unwrap();
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..4 {
+            for key_id in 0..10 {
+                let key = format!("key{}_{}", thread_id, key_id);
+                assert_eq!(store.get(key)?, Some("199".to_owned()));
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with(temp_dir.path(), options)?)
+}
+
+// Compacted logs get a hint file, and a damaged hint falls back to a replay.
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+
+    let hints: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("hint".as_ref()))
+        .collect();
+    assert!(!hints.is_empty());
+
+    let check = || -> Result<()> {
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for key_id in 0..10 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+        }
+        Ok(())
+    };
+    check()?;
+
+    for hint in &hints {
+        let len = fs::metadata(hint)?.len();
+        let mut file = OpenOptions::new().write(true).open(hint)?;
+        file.seek(SeekFrom::Start(len - 1))?;
+        file.write_all(b"X")?;
+    }
+    check()
+}
+
+// Should return the entries of a range or prefix in key order
+#[test]
+fn scan() -> Result<()> {
+    fn check_scan<E: KvsEngine>(engine: E) -> Result<()> {
+        for key in &["b", "a", "ab", "abc", "b1", "c"] {
+            engine.set(key.to_string(), key.to_uppercase())?;
+        }
+        engine.remove("b1".to_owned())?;
+        let keys = |scan: kvs::Scan| -> Result<Vec<String>> {
+            scan.map(|pair| pair.map(|(key, _)| key)).collect()
+        };
+
+        let pairs: Vec<_> = engine.scan(.., None)?.collect::<Result<_>>()?;
+        assert_eq!(pairs[0], ("a".to_owned(), "A".to_owned()));
+        assert_eq!(pairs.len(), 5);
+        assert_eq!(
+            keys(engine.scan("ab".to_owned()..="b".to_owned(), None)?)?,
+            vec!["ab", "abc", "b"]
+        );
+        assert_eq!(
+            keys(engine.scan("ab".to_owned().."c".to_owned(), Some(2))?)?,
+            vec!["ab", "abc"]
+        );
+        assert_eq!(
+            keys(engine.scan_prefix("a".to_owned(), None)?)?,
+            vec!["a", "ab", "abc"]
+        );
+        assert_eq!(keys(engine.scan_prefix("b".to_owned(), Some(0))?)?.len(), 0);
+        assert_eq!(keys(engine.scan_prefix("d".to_owned(), None)?)?.len(), 0);
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+// Should apply every operation of a batch in order
+#[test]
+fn write_batch() -> Result<()> {
+    fn check_batch<E: KvsEngine>(engine: E) -> Result<()> {
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        engine.set("key2".to_owned(), "value2".to_owned())?;
+
+        let mut batch = WriteBatch::new();
+        batch
+            .set("key3".to_owned(), "value3".to_owned())
+            .remove("key1".to_owned())
+            .set("key2".to_owned(), "first".to_owned())
+            .set("key2".to_owned(), "second".to_owned())
+            .remove("missing".to_owned());
+        assert_eq!(batch.len(), 5);
+        engine.write(batch)?;
+        engine.write(WriteBatch::new())?;
+
+        assert_eq!(engine.get("key1".to_owned())?, None);
+        assert_eq!(engine.get("key2".to_owned())?, Some("second".to_owned()));
+        assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+
+        // a set in a batch replaces the TTL of the key
+        let short = Duration::from_millis(100);
+        engine.set_with_ttl("key5".to_owned(), "value5".to_owned(), short)?;
+        let mut batch = WriteBatch::new();
+        batch
+            .set_with_ttl("key4".to_owned(), "value4".to_owned(), short)
+            .set("key5".to_owned(), "permanent".to_owned())
+            .set_with_ttl("key6".to_owned(), "value6".to_owned(), Duration::from_secs(60));
+        engine.write(batch)?;
+        assert_eq!(engine.get("key4".to_owned())?, Some("value4".to_owned()));
+        thread::sleep(2 * short);
+        assert_eq!(engine.get("key4".to_owned())?, None);
+        assert_eq!(engine.get("key5".to_owned())?, Some("permanent".to_owned()));
+        assert_eq!(engine.get("key6".to_owned())?, Some("value6".to_owned()));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batch(KvStore::open(temp_dir.path())?)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("second".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key6".to_owned())?, Some("value6".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batch(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+// Should drop a partially written batch as a whole
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "batched".to_owned())
+        .set("key2".to_owned(), "batched".to_owned());
+    store.write(batch)?;
+    drop(store);
+
+    let log = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("log".as_ref()))
+        .unwrap();
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new().write(true).open(&log)?.set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Should keep the values written by batches across compactions
+#[test]
+fn compact_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..100 {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..10 {
+            batch.set(format!("key{}", key_id), format!("{}", iter));
+        }
+        batch.remove(format!("key{}", iter % 10));
+        store.write(batch)?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key9".to_owned())?, None);
+    for key_id in 0..9 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    Ok(())
+}
+
+// Should count a batch as stale only once all of its keys are overwritten
+#[test]
+fn overwrite_batched_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut batch = WriteBatch::new();
+    for key_id in 0..100 {
+        batch.set(format!("key{}", key_id), "batched".to_owned());
+    }
+    let batch_len = {
+        let store = KvStore::open(temp_dir.path())?;
+        store.write(batch.clone())?;
+        fs::metadata(temp_dir.path().join("1.log"))?.len()
+    };
+
+    // a compaction seals the active log and writes to a log two generations later
+    let compacted = || temp_dir.path().join("4.log").exists();
+    let options = KvStoreOptions::new().compaction_threshold(2 * batch_len);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..99 {
+        store.set(format!("key{}", key_id), "single".to_owned())?;
+    }
+    assert!(!compacted());
+    store.remove("key99".to_owned())?;
+    store.write(batch.clone())?;
+    store.write(batch)?;
+    assert!(compacted());
+    Ok(())
+}
+
+// Should only swap values that match the expected ones
+#[test]
+fn compare_and_swap() -> Result<()> {
+    fn check_cas<E: KvsEngine>(engine: E) -> Result<()> {
+        let key = || "key1".to_owned();
+        assert!(engine.compare_and_swap(key(), None, Some("value1".to_owned()))?);
+        assert!(!engine.compare_and_swap(key(), None, Some("value2".to_owned()))?);
+        assert!(!engine.compare_and_swap(
+            key(),
+            Some("value2".to_owned()),
+            Some("value3".to_owned())
+        )?);
+        assert_eq!(engine.get(key())?, Some("value1".to_owned()));
+
+        assert!(engine.compare_and_swap(
+            key(),
+            Some("value1".to_owned()),
+            Some("value2".to_owned())
+        )?);
+        assert_eq!(engine.get(key())?, Some("value2".to_owned()));
+
+        assert!(engine.compare_and_swap(key(), Some("value2".to_owned()), None)?);
+        assert_eq!(engine.get(key())?, None);
+        assert!(engine.compare_and_swap(key(), None, None)?);
+        assert!(!engine.compare_and_swap(key(), Some("value2".to_owned()), None)?);
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_cas(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_cas(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+// Should not lose any increment made with compare-and-swap from several threads
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for _ in 0..100 {
+                loop {
+                    let current = store.get("counter".to_owned())?.unwrap();
+                    let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                    if store.compare_and_swap("counter".to_owned(), Some(current), Some(next))? {
+                        break;
+                    }
+                }
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+    Ok(())
+}
+
+// Should hide keys once their TTL has elapsed, also after a restart
+#[test]
+fn set_with_ttl() -> Result<()> {
+    fn check_ttl<E: KvsEngine>(engine: &E) -> Result<()> {
+        // Only the keys meant to expire get the short TTL, so nothing else
+        // depends on how long the writes take.
+        let short = Duration::from_millis(100);
+        let long = Duration::from_secs(60);
+        engine.set_with_ttl("token1".to_owned(), "value1".to_owned(), short)?;
+        engine.set_with_ttl("token2".to_owned(), "value2".to_owned(), short)?;
+        // a plain set makes the key permanent
+        engine.set("token2".to_owned(), "value2".to_owned())?;
+        engine.set_with_ttl("token3".to_owned(), "value3".to_owned(), short)?;
+        let token = |n: u8| format!("token{}", n).into_bytes();
+        let cas_ttl = |key, expected, value: &[u8], ttl| {
+            engine.compare_and_swap_bytes_with_ttl(key, expected, value.to_vec(), ttl)
+        };
+        // without a ttl the key keeps its expiry time
+        assert!(cas_ttl(token(3), Some(b"value3".to_vec()), b"new3", None)?);
+        engine.set_with_ttl("token4".to_owned(), "value4".to_owned(), long)?;
+        assert_eq!(engine.get("token4".to_owned())?, Some("value4".to_owned()));
+        assert!(cas_ttl(token(5), None, b"value5", Some(long))?);
+        assert!(!cas_ttl(token(5), None, b"other", Some(long))?);
+
+        thread::sleep(2 * short);
+        assert_eq!(engine.get("token1".to_owned())?, None);
+        assert_eq!(engine.get("token2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(engine.get("token4".to_owned())?, Some("value4".to_owned()));
+        let keys: Vec<_> = engine
+            .scan(.., None)?
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        assert_eq!(keys, vec!["token2", "token4", "token5"]);
+        assert!(matches!(
+            engine.remove("token3".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+        assert!(engine.compare_and_swap("token1".to_owned(), None, Some("new".to_owned()))?);
+        assert_eq!(engine.get("token1".to_owned())?, Some("new".to_owned()));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_ttl(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("token3".to_owned())?, None);
+    assert_eq!(store.get("token4".to_owned())?, Some("value4".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_ttl(&SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+// Should drop expired entries from the logs when compacting
+#[test]
+fn compact_expired_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(64 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    // files come and go while the compaction thread runs, so errors are skipped
+    let dir_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|res| res.and_then(|entry| entry.metadata()).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    };
+
+    let value = "x".repeat(1000);
+    for key_id in 0..1000 {
+        let key = format!("session{}", key_id);
+        store.set_with_ttl(key, value.clone(), Duration::from_millis(1))?;
+    }
+    assert!(dir_size() > 1000 * 1000);
+    thread::sleep(Duration::from_millis(10));
+
+    // Expired entries are not counted as stale, so a key is overwritten until
+    // a compaction starts, which seals the log and writes two generations later.
+    let mut iter = 0;
+    while !temp_dir.path().join("3.log").exists() {
+        assert!(iter < 100_000, "no compaction is triggered");
+        store.set("counter".to_owned(), format!("{}", iter))?;
+        iter += 1;
+    }
+    // the compaction thread removes the sealed log once it is done
+    let start = Instant::now();
+    while temp_dir.path().join("1.log").exists() {
+        assert!(start.elapsed() < Duration::from_secs(30), "the compaction does not finish");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(dir_size() < 100 * 1000);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.scan_prefix("session".to_owned(), None)?.count(), 0);
+    assert_eq!(store.get("counter".to_owned())?, Some(format!("{}", iter - 1)));
+    Ok(())
+}
+
+// A snapshot should not see the writes made after it was taken
+#[test]
+fn snapshot() -> Result<()> {
+    fn check_snapshot<E: KvsEngine>(engine: E) -> Result<()> {
+        for key in &["a", "b", "c"] {
+            engine.set(key.to_string(), key.to_uppercase())?;
+        }
+        let snapshot = engine.snapshot()?;
+        engine.set("a".to_owned(), "A2".to_owned())?;
+        engine.remove("b".to_owned())?;
+        engine.set("ab".to_owned(), "AB".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch.set("c", "C2").set("d", "D");
+        engine.write(batch)?;
+
+        assert_eq!(snapshot.get("a".to_owned())?, Some("A".to_owned()));
+        assert_eq!(snapshot.get("b".to_owned())?, Some("B".to_owned()));
+        assert_eq!(snapshot.get("ab".to_owned())?, None);
+        let keys = |scan: kvs::Scan| -> Result<Vec<String>> {
+            scan.map(|pair| pair.map(|(key, _)| key)).collect()
+        };
+        let pairs: Vec<_> = snapshot.scan(.., None)?.collect::<Result<_>>()?;
+        assert_eq!(pairs[2], ("c".to_owned(), "C".to_owned()));
+        assert_eq!(pairs.len(), 3);
+        assert_eq!(
+            keys(snapshot.scan("a".to_owned().."c".to_owned(), None)?)?,
+            vec!["a", "b"]
+        );
+        assert_eq!(keys(snapshot.scan_prefix("a".to_owned(), None)?)?, vec!["a"]);
+
+        assert_eq!(engine.get("a".to_owned())?, Some("A2".to_owned()));
+        assert_eq!(
+            keys(engine.scan(.., None)?)?,
+            vec!["a", "ab", "c", "d"]
+        );
+        // a new snapshot sees the writes
+        let snapshot = engine.snapshot()?;
+        assert_eq!(snapshot.get("c".to_owned())?, Some("C2".to_owned()));
+        // and a scan of a snapshot ignores the writes made while it runs
+        let mut scan = snapshot.scan(.., None)?;
+        assert_eq!(scan.next().transpose()?, Some(("a".to_owned(), "A2".to_owned())));
+        engine.remove("c".to_owned())?;
+        engine.set("b".to_owned(), "B2".to_owned())?;
+        assert_eq!(keys(scan)?, vec!["ab", "c", "d"]);
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+// The logs a snapshot reads should outlive compactions until it is dropped
+#[test]
+fn snapshot_pins_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let gens = || -> Vec<u64> {
+        let mut gens: Vec<u64> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension() != Some("log".as_ref()) {
+                    return None;
+                }
+                path.file_stem()?.to_str()?.parse().ok()
+            })
+            .collect();
+        gens.sort_unstable();
+        gens
+    };
+    let wait_for = |done: &dyn Fn() -> bool| {
+        let start = Instant::now();
+        while !done() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    let first_gen = gens()[0];
+    let snapshot = store.snapshot()?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    // a compaction left a hint file behind
+    wait_for(&|| {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .any(|entry| entry.unwrap().path().extension() == Some("hint".as_ref()))
+    });
+
+    assert_eq!(gens()[0], first_gen);
+    for key_id in 0..10 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id))?,
+            Some("old".to_owned())
+        );
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    assert_eq!(snapshot.scan(.., None)?.count(), 10);
+
+    drop(snapshot);
+    wait_for(&|| gens()[0] != first_gen);
+    assert_ne!(gens()[0], first_gen);
+    Ok(())
+}
+
+// Snapshots taken while batches and compactions run should see consistent totals
+#[test]
+fn concurrent_snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for account in 0..10 {
+        store.set(format!("account{}", account), "100".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for iter in 0..500 {
+                // move one unit between two accounts
+                let (from, to) = (iter % 10, (iter * 7 + 3) % 10);
+                let balance = |account| -> u64 {
+                    store.get(format!("account{}", account)).unwrap().unwrap().parse().unwrap()
+                };
+                let mut batch = WriteBatch::new();
+                batch.set(format!("account{}", from), (balance(from) - 1).to_string());
+                batch.set(format!("account{}", to), (balance(to) + 1).to_string());
+                store.write(batch).unwrap();
+            }
+        })
+    };
+    for _ in 0..100 {
+        let snapshot = store.snapshot()?;
+        let mut total = 0;
+        for pair in snapshot.scan_prefix("account".to_owned(), None)? {
+            total += pair?.1.parse::<u64>().unwrap();
+        }
+        assert_eq!(total, 1000);
+    }
+    writer.join().unwrap();
+    Ok(())
+}
+
+// Transactions should read their own writes and fail on conflicting commits
+#[test]
+fn transaction() -> Result<()> {
+    fn check_transaction<E: KvsEngine>(engine: E) -> Result<()> {
+        engine.set("from".to_owned(), "100".to_owned())?;
+        engine.set("to".to_owned(), "0".to_owned())?;
+
+        let mut txn = engine.begin()?;
+        assert_eq!(txn.get("from".to_owned())?, Some("100".to_owned()));
+        txn.set("from".to_owned(), "90".to_owned())?;
+        txn.set("to".to_owned(), "10".to_owned())?;
+        assert_eq!(txn.get("from".to_owned())?, Some("90".to_owned()));
+        assert_eq!(engine.get("from".to_owned())?, Some("100".to_owned()));
+        txn.commit()?;
+        assert_eq!(engine.get("from".to_owned())?, Some("90".to_owned()));
+        assert_eq!(engine.get("to".to_owned())?, Some("10".to_owned()));
+
+        // a key read by the transaction is changed before it commits
+        let mut txn = engine.begin()?;
+        txn.get("from".to_owned())?;
+        engine.set("from".to_owned(), "50".to_owned())?;
+        assert_eq!(txn.get("from".to_owned())?, Some("90".to_owned()));
+        txn.set("to".to_owned(), "20".to_owned())?;
+        match txn.commit() {
+            Err(KvsError::Conflict) => {}
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+        assert_eq!(engine.get("to".to_owned())?, Some("10".to_owned()));
+
+        // blind writes do not conflict
+        let mut txn = engine.begin()?;
+        txn.set("to".to_owned(), "30".to_owned())?;
+        engine.set("to".to_owned(), "40".to_owned())?;
+        txn.commit()?;
+        assert_eq!(engine.get("to".to_owned())?, Some("30".to_owned()));
+
+        let mut txn = engine.begin()?;
+        match txn.remove("missing".to_owned()) {
+            Err(KvsError::KeyNotFound) => {}
+            other => panic!("expected a missing key, got {:?}", other),
+        }
+        txn.remove("to".to_owned())?;
+        assert_eq!(txn.get("to".to_owned())?, None);
+        // dropping the transaction aborts it
+        drop(txn);
+        assert_eq!(engine.get("to".to_owned())?, Some("30".to_owned()));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transaction(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transaction(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+// Transfers retried on conflicts should neither lose nor create money
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for account in 0..4 {
+        store.set(format!("account{}", account), "100".to_owned())?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for iter in 0..50 {
+                let from = format!("account{}", (thread_id + iter) % 4);
+                let to = format!("account{}", (thread_id + iter + 1) % 4);
+                loop {
+                    let mut txn = store.begin()?;
+                    let balance = |value: Option<String>| value.unwrap().parse::<u64>().unwrap();
+                    let from_balance = balance(txn.get(from.clone())?);
+                    let to_balance = balance(txn.get(to.clone())?);
+                    if from_balance > 0 {
+                        txn.set(from.clone(), (from_balance - 1).to_string())?;
+                        txn.set(to.clone(), (to_balance + 1).to_string())?;
+                    }
+                    match txn.commit() {
+                        Err(KvsError::Conflict) => continue,
+                        result => break result?,
+                    }
+                }
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let mut total = 0;
+    for account in 0..4 {
+        total += store
+            .get(format!("account{}", account))?
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+    }
+    assert_eq!(total, 400);
+    Ok(())
+}
+
+// Should store keys and values that are not valid UTF-8
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    fn check_bytes<E: KvsEngine>(engine: &E) -> Result<()> {
+        let key = vec![0xff, 0x00, 0xfe];
+        let value = vec![0x00, 0xc3, 0x28, 0xff];
+        engine.set_bytes(key.clone(), value.clone())?;
+        engine.set_bytes(vec![0xff, 0x01], vec![0x80])?;
+        engine.set("text".to_owned(), "value".to_owned())?;
+        assert_eq!(engine.get_bytes(key.clone())?, Some(value.clone()));
+
+        let pairs = engine
+            .scan_prefix_bytes(vec![0xff], None)?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            pairs,
+            vec![(key.clone(), value), (vec![0xff, 0x01], vec![0x80])]
+        );
+
+        match engine.scan(.., None)?.collect::<Result<Vec<_>>>() {
+            Err(KvsError::Utf8Error(_)) => {}
+            other => panic!("expected a UTF-8 error, got {:?}", other),
+        }
+        engine.set_bytes(b"text".to_vec(), vec![0xc3, 0x28])?;
+        match engine.get("text".to_owned()) {
+            Err(KvsError::Utf8Error(_)) => {}
+            other => panic!("expected a UTF-8 error, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_bytes(&store)?;
+    drop(store);
+    // the binary keys survive a restart
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_bytes(vec![0xff, 0x00, 0xfe])?,
+        Some(vec![0x00, 0xc3, 0x28, 0xff])
+    );
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_bytes(&SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -1,5 +1,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
 
 use kvs::thread_pool::*;
 use kvs::Result;
@@ -29,6 +31,24 @@ fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
     Ok(())
 }
 
+fn drop_waits_for_jobs<P: ThreadPool>() -> Result<()> {
+    const TASK_NUM: usize = 20;
+
+    let pool = P::new(2)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    Ok(())
+}
+
 fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     const TASK_NUM: usize = 1000;
 
@@ -46,6 +66,21 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     spawn_counter(pool)
 }
 
+fn spawn_does_not_wait_for_job<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let (start, started) = mpsc::channel();
+    let (done, finished) = mpsc::channel();
+    pool.spawn(move || {
+        // never signalled if `spawn` waits for the job to end
+        let ok = started.recv_timeout(Duration::from_secs(5)).is_ok();
+        done.send(ok).unwrap();
+    });
+
+    let _ = start.send(());
+    assert!(finished.recv().unwrap());
+    Ok(())
+}
+
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
@@ -68,3 +103,33 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn naive_thread_pool_spawn_does_not_wait_for_job() -> Result<()> {
+    spawn_does_not_wait_for_job::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_does_not_wait_for_job() -> Result<()> {
+    spawn_does_not_wait_for_job::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_does_not_wait_for_job() -> Result<()> {
+    spawn_does_not_wait_for_job::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_drop_waits_for_jobs() -> Result<()> {
+    drop_waits_for_jobs::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_drop_waits_for_jobs() -> Result<()> {
+    drop_waits_for_jobs::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_drop_waits_for_jobs() -> Result<()> {
+    drop_waits_for_jobs::<RayonThreadPool>()
+}